@@ -0,0 +1,56 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+/// Where kanata's TCP server is listening
+///
+/// The host may be an IPv4 or IPv6 address or a hostname. Hostnames are resolved on
+/// every connection attempt, so a changing address (e.g. a VM or container restarting)
+/// is picked up on reconnect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KanataAddr {
+    pub host: String,
+    pub port: u16,
+}
+
+impl KanataAddr {
+    /// Creates an address, accepting IPv6 hosts with or without brackets
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        let host = host.into();
+        let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(unbracketed) => unbracketed.to_string(),
+            None => host,
+        };
+        KanataAddr { host, port }
+    }
+
+    /// Resolves the host to the socket addresses to try, in order
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = (self.host.as_str(), self.port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} did not resolve to any address", self.host),
+            ));
+        }
+        Ok(addrs)
+    }
+}
+
+impl From<SocketAddr> for KanataAddr {
+    fn from(addr: SocketAddr) -> Self {
+        KanataAddr {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        }
+    }
+}
+
+impl fmt::Display for KanataAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => write!(f, "[{}]:{}", self.host, self.port),
+            _ => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
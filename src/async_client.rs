@@ -0,0 +1,162 @@
+//! Async (tokio) client for kanata's TCP server
+//!
+//! Unlike the blocking [`crate::KanataClient`], it uses a single connection and
+//! doesn't reconnect; connect again once the [`MessageStream`] ends.
+
+use crate::addr::KanataAddr;
+use crate::client::CONNECT_TIMEOUT;
+use crate::protocol::{ClientMessage, ServerMessage};
+use futures_core::Stream;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// Sending half of an async connection to kanata
+///
+/// Cloning the client is cheap and every clone writes to the same connection, so
+/// messages can be sent concurrently from multiple tasks.
+#[derive(Clone)]
+pub struct KanataClient {
+    addr: KanataAddr,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+}
+
+impl KanataClient {
+    /// Connects to kanata, returning the client and the stream of messages kanata sends
+    pub async fn connect(addr: impl Into<KanataAddr>) -> io::Result<(KanataClient, MessageStream)> {
+        let addr = addr.into();
+        let connect = TcpStream::connect((addr.host.as_str(), addr.port));
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))??;
+        let (reader, writer) = stream.into_split();
+
+        let client = KanataClient {
+            addr,
+            writer: Arc::new(Mutex::new(writer)),
+        };
+        let messages = MessageStream {
+            lines: BufReader::new(reader).lines(),
+        };
+        Ok((client, messages))
+    }
+
+    pub fn addr(&self) -> &KanataAddr {
+        &self.addr
+    }
+
+    /// Sends `msg` to kanata
+    pub async fn send(&self, msg: &ClientMessage) -> io::Result<()> {
+        let line = msg.to_line()?;
+        self.writer.lock().await.write_all(line.as_bytes()).await
+    }
+}
+
+/// Messages sent by kanata, ending when the connection is closed or lost
+///
+/// Read errors are logged and end the stream, and lines that aren't a
+/// [`ServerMessage`] are skipped.
+pub struct MessageStream {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+}
+
+impl Stream for MessageStream {
+    type Item = ServerMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerMessage>> {
+        loop {
+            let line = match Pin::new(&mut self.lines).poll_next_line(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(Some(line))) => line,
+                Poll::Ready(Ok(None)) => {
+                    log::debug!("connection closed by kanata");
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Err(e)) => {
+                    log::error!("connection lost: {}", e);
+                    return Poll::Ready(None);
+                }
+            };
+
+            log::trace!("received: {}", line);
+            match serde_json::from_str::<ServerMessage>(&line) {
+                Ok(msg) => return Poll::Ready(Some(msg)),
+                Err(e) => log::trace!("ignoring line {:?}: {}", line, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use tokio::net::TcpListener;
+
+    async fn next(messages: &mut MessageStream) -> Option<ServerMessage> {
+        poll_fn(|cx| Pin::new(&mut *messages).poll_next(cx)).await
+    }
+
+    #[test]
+    fn round_trip() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                let mut received = Vec::new();
+                for _ in 0..2 {
+                    received.push(lines.next_line().await.unwrap().unwrap());
+                }
+                writer
+                    .write_all(b"not json\n{\"CurrentLayerName\":{\"name\":\"nav\"}}\n")
+                    .await
+                    .unwrap();
+                received
+            });
+
+            let (client, mut messages) = KanataClient::connect(addr).await.unwrap();
+            // Clones send concurrently from their own tasks
+            let sends: Vec<_> = [
+                ClientMessage::RequestCurrentLayerName {},
+                ClientMessage::RequestLayerNames {},
+            ]
+            .into_iter()
+            .map(|msg| {
+                let client = client.clone();
+                tokio::spawn(async move { client.send(&msg).await })
+            })
+            .collect();
+            for send in sends {
+                send.await.unwrap().unwrap();
+            }
+
+            let mut received = server.await.unwrap();
+            received.sort();
+            assert_eq!(
+                received,
+                [
+                    "{\"RequestCurrentLayerName\":{}}",
+                    "{\"RequestLayerNames\":{}}"
+                ]
+            );
+            assert!(matches!(
+                next(&mut messages).await,
+                Some(ServerMessage::CurrentLayerName { name }) if name == "nav"
+            ));
+            // The server hung up
+            assert!(next(&mut messages).await.is_none());
+        });
+    }
+}
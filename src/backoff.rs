@@ -0,0 +1,154 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Delay after the first failed attempt
+    pub initial: Duration,
+    /// Upper bound for the delay
+    pub max: Duration,
+    /// Factor the delay grows by after each failed attempt
+    pub multiplier: f64,
+    /// Consecutive failed attempts after which to give up, or `None` to retry forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay before the next attempt after `failures` consecutive failures
+    ///
+    /// The delay is picked at random between half and all of the exponential delay, so
+    /// that several clients don't reconnect in lockstep.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = i32::try_from(failures.saturating_sub(1)).unwrap_or(i32::MAX);
+        let secs = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        // NaN for a zero initial delay times an infinite growth
+        let secs = if secs.is_nan() { 0.0 } else { secs };
+        let delay = secs.clamp(0.0, self.max.as_secs_f64());
+
+        Duration::try_from_secs_f64(delay * (0.5 + 0.5 * random_fraction())).unwrap_or(self.max)
+    }
+
+    /// Whether to give up after `failures` consecutive failed attempts
+    pub fn exhausted(&self, failures: u32) -> bool {
+        self.max_attempts.is_some_and(|max| failures >= max)
+    }
+}
+
+/// Returns a random number in `[0, 1)` without pulling in an RNG crate
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(initial: f64, max: f64, multiplier: f64) -> Backoff {
+        Backoff {
+            initial: Duration::from_secs_f64(initial),
+            max: Duration::from_secs_f64(max),
+            multiplier,
+            max_attempts: None,
+        }
+    }
+
+    /// Asserts that the delay is between half and all of `secs`
+    fn assert_delay(backoff: &Backoff, failures: u32, secs: f64) {
+        for _ in 0..20 {
+            let delay = backoff.delay(failures).as_secs_f64();
+            assert!(
+                delay >= secs / 2.0 && delay <= secs,
+                "delay after {} failures is {}s, expected {}s to {}s",
+                failures,
+                delay,
+                secs / 2.0,
+                secs
+            );
+        }
+    }
+
+    #[test]
+    fn delay_grows_exponentially_up_to_max() {
+        let backoff = backoff(1.0, 30.0, 2.0);
+        assert_delay(&backoff, 0, 1.0);
+        assert_delay(&backoff, 1, 1.0);
+        assert_delay(&backoff, 2, 2.0);
+        assert_delay(&backoff, 5, 16.0);
+        assert_delay(&backoff, 6, 30.0);
+    }
+
+    #[test]
+    fn delay_saturates_for_huge_exponents() {
+        let backoff = backoff(1.0, 30.0, 2.0);
+        assert_delay(&backoff, u32::MAX, 30.0);
+        // Larger than i32::MAX, which powi takes
+        assert_delay(&backoff, 1 << 31, 30.0);
+        assert_delay(&backoff, 1 << 31 | 1, 30.0);
+    }
+
+    #[test]
+    fn delay_with_infinite_multiplier() {
+        let backoff = backoff(1.0, 30.0, f64::INFINITY);
+        assert_delay(&backoff, 1, 1.0);
+        assert_delay(&backoff, 2, 30.0);
+        assert_delay(&backoff, u32::MAX, 30.0);
+    }
+
+    #[test]
+    fn delay_with_zero_initial_is_zero() {
+        for multiplier in [1.0, 2.0, f64::INFINITY] {
+            let backoff = backoff(0.0, 30.0, multiplier);
+            for failures in [0, 1, 2, 100, u32::MAX] {
+                assert_eq!(backoff.delay(failures), Duration::ZERO);
+            }
+        }
+    }
+
+    #[test]
+    fn delay_stays_in_range_for_odd_settings() {
+        assert_delay(&backoff(1.0, 30.0, f64::NAN), 3, 0.0);
+        assert_delay(&backoff(1.0, 30.0, -2.0), 2, 0.0);
+        assert_delay(&backoff(10.0, 5.0, 2.0), 1, 5.0);
+        assert_delay(&backoff(0.0, 0.0, 2.0), 1, 0.0);
+
+        let backoff = Backoff {
+            max: Duration::MAX,
+            ..backoff(1.0, 0.0, f64::INFINITY)
+        };
+        for _ in 0..20 {
+            assert!(backoff.delay(2) >= Duration::MAX / 2);
+        }
+    }
+
+    #[test]
+    fn exhausted_after_max_attempts() {
+        let mut backoff = Backoff::default();
+        assert!(!backoff.exhausted(u32::MAX));
+        backoff.max_attempts = Some(3);
+        assert!(!backoff.exhausted(2));
+        assert!(backoff.exhausted(3));
+        assert!(backoff.exhausted(4));
+    }
+
+    #[test]
+    fn random_fraction_is_below_one() {
+        for _ in 0..100 {
+            let fraction = random_fraction();
+            assert!((0.0..1.0).contains(&fraction));
+        }
+    }
+}
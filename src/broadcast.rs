@@ -0,0 +1,184 @@
+use crate::config::BroadcastConfig;
+use anyhow::{bail, Context};
+use kanata_layer_observer::ServerMessage;
+use std::io::Write;
+use std::net::{Ipv4Addr, TcpListener};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Messages a subscriber may fall behind by before it is dropped
+const SUBSCRIBER_BACKLOG: usize = 256;
+
+/// How long to wait before accepting again after an error
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Re-broadcasts kanata's messages as newline-delimited JSON to local subscribers
+///
+/// New subscribers first get the layer names and current layer, if known. Anything
+/// subscribers send is ignored.
+#[derive(Clone, Default)]
+pub struct Broadcaster {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    subscribers: Vec<SyncSender<Arc<str>>>,
+    layer_names: Option<Vec<String>>,
+    layer: Option<String>,
+}
+
+impl Broadcaster {
+    /// Starts listening on the socket and port in `config`, accepting subscribers on
+    /// threads named `name`
+    pub fn start(name: &str, config: &BroadcastConfig) -> anyhow::Result<Self> {
+        let broadcaster = Broadcaster::default();
+
+        if let Some(path) = &config.socket {
+            let path = shellexpand::tilde(path).to_string();
+            broadcaster
+                .listen_unix(name, &path)
+                .with_context(|| format!("failed to listen on {}", path))?;
+        }
+        if let Some(port) = config.port {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+                .with_context(|| format!("failed to listen on 127.0.0.1:{}", port))?;
+            log::info!("broadcasting on 127.0.0.1:{}", port);
+            broadcaster.accept(name, listener, |listener| {
+                listener.accept().map(|(stream, _)| stream)
+            });
+        }
+
+        Ok(broadcaster)
+    }
+
+    #[cfg(unix)]
+    fn listen_unix(&self, name: &str, path: &str) -> anyhow::Result<()> {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        // A socket left behind by an observer that didn't exit cleanly can be replaced,
+        // one that still accepts connections belongs to a running observer
+        if std::fs::metadata(path).is_ok() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("another process is already listening");
+            }
+            std::fs::remove_file(path).context("failed to remove stale socket")?;
+        }
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        log::info!("broadcasting on {}", path);
+        self.accept(name, listener, |listener| {
+            listener.accept().map(|(stream, _)| stream)
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn listen_unix(&self, _name: &str, _path: &str) -> anyhow::Result<()> {
+        bail!("Unix domain sockets are not supported on this platform, use `port` instead");
+    }
+
+    /// Subscribes every connection accepted from `listener` on a new thread
+    fn accept<L, S>(&self, name: &str, listener: L, accept: fn(&L) -> std::io::Result<S>)
+    where
+        L: Send + 'static,
+        S: Write + Send + 'static,
+    {
+        let broadcaster = self.clone();
+        spawn_named(name, move || loop {
+            match accept(&listener) {
+                Ok(stream) => broadcaster.subscribe(stream),
+                Err(e) => {
+                    log::error!("failed to accept subscriber: {}", e);
+                    // Errors like running out of file descriptors tend to persist
+                    std::thread::sleep(ACCEPT_RETRY_DELAY);
+                }
+            }
+        });
+    }
+
+    /// Sends `msg` to every subscriber and remembers the layer state it carries
+    pub fn send(&self, msg: &ServerMessage) {
+        let line: Arc<str> = match serde_json::to_string(msg) {
+            Ok(json) => format!("{}\n", json).into(),
+            Err(e) => {
+                log::error!("failed to serialize message for subscribers: {}", e);
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().expect("broadcast state poisoned");
+        match msg {
+            ServerMessage::LayerChange { new } => state.layer = Some(new.clone()),
+            ServerMessage::CurrentLayerName { name } => state.layer = Some(name.clone()),
+            ServerMessage::LayerNames { names } => state.layer_names = Some(names.clone()),
+            _ => {}
+        }
+        state
+            .subscribers
+            .retain(|subscriber| match subscriber.try_send(Arc::clone(&line)) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("dropping subscriber that stopped reading");
+                    false
+                }
+                // The writer thread exits once the subscriber disconnects
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    fn subscribe<W: Write + Send + 'static>(&self, stream: W) {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+
+        let mut state = self.state.lock().expect("broadcast state poisoned");
+        let current = [
+            state
+                .layer_names
+                .clone()
+                .map(|names| ServerMessage::LayerNames { names }),
+            state
+                .layer
+                .clone()
+                .map(|name| ServerMessage::CurrentLayerName { name }),
+        ];
+        for msg in current.into_iter().flatten() {
+            if let Ok(json) = serde_json::to_string(&msg) {
+                // Fits, the channel is new and larger than the current state
+                let _ = sender.try_send(format!("{}\n", json).into());
+            }
+        }
+        state.subscribers.push(sender);
+        log::debug!("subscriber connected, {} total", state.subscribers.len());
+        drop(state);
+
+        let name = std::thread::current()
+            .name()
+            .unwrap_or_default()
+            .to_string();
+        spawn_named(&name, move || write_lines(stream, receiver));
+    }
+}
+
+/// Writes every line to `stream` until the subscriber disconnects or is dropped
+fn write_lines(mut stream: impl Write, receiver: Receiver<Arc<str>>) {
+    for line in receiver {
+        if let Err(e) = stream
+            .write_all(line.as_bytes())
+            .and_then(|()| stream.flush())
+        {
+            log::debug!("subscriber disconnected: {}", e);
+            return;
+        }
+    }
+}
+
+fn spawn_named(name: &str, f: impl FnOnce() + Send + 'static) {
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .expect("failed to spawn broadcast thread");
+}
@@ -0,0 +1,492 @@
+use crate::command::Action;
+use crate::config::{did_you_mean, env_overrides, ActionConfig, CommandConfig, Config, Segment};
+use crate::layers::LayerPattern;
+use serde::Deserialize;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use toml::de::{DeTable, DeValue};
+use toml::Spanned;
+
+/// A problem with the config file, at the key or array entry `span` points to if known
+struct Diagnostic {
+    span: Option<Range<usize>>,
+    message: String,
+}
+
+/// Checks the config file at `path` without connecting to kanata, printing the
+/// problems found, and returns the exit code
+pub fn check(path: &str) -> i32 {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Failed to read config file {}: {}", path, e);
+            return 1;
+        }
+    };
+
+    let diagnostics = diagnose(&contents);
+    if diagnostics.is_empty() {
+        println!("{}: ok", path);
+        return 0;
+    }
+    for diagnostic in &diagnostics {
+        match &diagnostic.span {
+            Some(span) => {
+                let (line, column) = line_column(&contents, span.start);
+                eprintln!("{}:{}:{}: {}", path, line, column, diagnostic.message);
+            }
+            None => eprintln!("{}: {}", path, diagnostic.message),
+        }
+    }
+    1
+}
+
+fn diagnose(contents: &str) -> Vec<Diagnostic> {
+    let table = match DeTable::parse(contents) {
+        Ok(table) => table,
+        Err(e) => return vec![toml_error(&e)],
+    };
+
+    let config = match deserialize(&table) {
+        Ok(config) => config,
+        Err(diagnostics) => return diagnostics,
+    };
+    // Only checked once the file itself is valid, as overrides have no position in it
+    let overrides = env_overrides();
+    let config = if overrides.is_empty() {
+        config
+    } else {
+        match Config::parse(contents, &overrides) {
+            Ok(config) => config,
+            Err(e) => {
+                return vec![Diagnostic {
+                    span: None,
+                    message: format!("{:#}", e).trim_end().to_string(),
+                }]
+            }
+        }
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut error = |path: Vec<Segment>, message: String| {
+        diagnostics.push(Diagnostic {
+            span: locate(&table, &path),
+            message,
+        });
+    };
+    if let Err(invalid) = config.validate_options() {
+        error(invalid.path, format!("{:#}", invalid.error));
+    }
+
+    // Checked one by one instead of with Config::layer_handlers to report every problem
+    for (pattern, layer) in &config.layers {
+        let path = vec![Segment::key("layers"), Segment::key(pattern)];
+        if let Err(e) = LayerPattern::parse(pattern) {
+            error(path.clone(), format!("{:#}", e));
+        }
+        check_actions(&mut error, &path, "commands", &layer.commands);
+    }
+    for (i, rule) in config.rules.iter().enumerate() {
+        let path = vec![Segment::key("rules"), Segment::Index(i)];
+        for (key, pattern) in [("from", &rule.from), ("to", &rule.to)] {
+            if let Some(Err(e)) = pattern.as_deref().map(LayerPattern::parse) {
+                error(with(&path, Segment::key(key)), format!("{:#}", e));
+            }
+        }
+        check_actions(&mut error, &path, "on_enter", &rule.on_enter);
+        check_actions(&mut error, &path, "on_exit", &rule.on_exit);
+    }
+
+    for (path, program) in programs(&config) {
+        if let Err(message) = find_program(program) {
+            error(path, message);
+        }
+    }
+    diagnostics
+}
+
+/// Compiles each entry of the action list at `path.key`
+fn check_actions(
+    error: &mut impl FnMut(Vec<Segment>, String),
+    path: &[Segment],
+    key: &str,
+    actions: &[ActionConfig],
+) {
+    let path = with(path, Segment::key(key));
+    for (i, action) in actions.iter().enumerate() {
+        if let Err(e) = Action::new(action) {
+            error(with(&path, Segment::Index(i)), format!("{:#}", e));
+        }
+    }
+}
+
+/// Returns every script and program the config refers to, with its path
+fn programs(config: &Config) -> Vec<(Vec<Segment>, &str)> {
+    let mut programs = Vec::new();
+    let key = Segment::key;
+
+    settings_programs(
+        Vec::new(),
+        &config.script_path,
+        &config.command,
+        &config.on_config_reload,
+        &mut programs,
+    );
+    for (i, instance) in config.kanata.iter().enumerate() {
+        settings_programs(
+            vec![key("kanata"), Segment::Index(i)],
+            &instance.script_path,
+            &instance.command,
+            &instance.on_config_reload,
+            &mut programs,
+        );
+    }
+
+    let message_push = vec![key("message_push")];
+    if let Some(script_path) = &config.message_push.script_path {
+        programs.push((
+            with(&message_push, key("script_path")),
+            script_path.as_str(),
+        ));
+    }
+    for (value, script_path) in &config.message_push.routes {
+        let path = with(&with(&message_push, key("routes")), key(value));
+        programs.push((path, script_path.as_str()));
+    }
+
+    for (pattern, layer) in &config.layers {
+        let path = vec![key("layers"), key(pattern), key("commands")];
+        action_programs(&path, &layer.commands, &mut programs);
+    }
+    for (i, rule) in config.rules.iter().enumerate() {
+        let path = vec![key("rules"), Segment::Index(i)];
+        action_programs(&with(&path, key("on_enter")), &rule.on_enter, &mut programs);
+        action_programs(&with(&path, key("on_exit")), &rule.on_exit, &mut programs);
+    }
+    programs
+}
+
+/// Adds the scripts and programs of the top level or of a `[[kanata]]` entry at `path`
+fn settings_programs<'a>(
+    path: Vec<Segment>,
+    script_path: &'a Option<String>,
+    command: &'a Option<Vec<String>>,
+    on_config_reload: &'a Option<String>,
+    programs: &mut Vec<(Vec<Segment>, &'a str)>,
+) {
+    let key = Segment::key;
+    if let Some(script_path) = script_path {
+        programs.push((with(&path, key("script_path")), script_path.as_str()));
+    }
+    if let Some(program) = command.as_ref().and_then(|argv| argv.first()) {
+        let path = with(&with(&path, key("command")), Segment::Index(0));
+        programs.push((path, program.as_str()));
+    }
+    if let Some(script_path) = on_config_reload {
+        programs.push((with(&path, key("on_config_reload")), script_path.as_str()));
+    }
+}
+
+fn action_programs<'a>(
+    path: &[Segment],
+    actions: &'a [ActionConfig],
+    programs: &mut Vec<(Vec<Segment>, &'a str)>,
+) {
+    for (i, action) in actions.iter().enumerate() {
+        let path = with(path, Segment::Index(i));
+        let (path, command) = match action {
+            ActionConfig::Command(command) => (path, command),
+            ActionConfig::Table(table) => {
+                if let Some(actions) = &table.commands {
+                    let path = with(&path, Segment::key("commands"));
+                    action_programs(&path, actions, programs);
+                }
+                let Some(command) = &table.command else {
+                    continue;
+                };
+                (with(&path, Segment::key("command")), command)
+            }
+        };
+        match command {
+            CommandConfig::Script(script_path) => programs.push((path, script_path.as_str())),
+            CommandConfig::Argv(argv) => {
+                // A program picked by a placeholder is only known once the layer is
+                if let Some(program) = argv.first().filter(|program| !program.contains('{')) {
+                    programs.push((with(&path, Segment::Index(0)), program.as_str()));
+                }
+            }
+        }
+    }
+}
+
+/// Checks that `program` can be executed, either as a path or by looking it up in
+/// `PATH` like [`std::process::Command`]
+fn find_program(program: &str) -> Result<(), String> {
+    let expanded = shellexpand::tilde(program);
+    if expanded.contains('/') {
+        let path = Path::new(expanded.as_ref());
+        return match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Err(format!("{} is a directory", program)),
+            Ok(_) if !is_executable(path) => Err(format!("{} is not executable", program)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}: {}", program, e)),
+        };
+    }
+
+    let found = std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(program)))
+    });
+    if found {
+        Ok(())
+    } else {
+        Err(format!("{} not found in PATH", program))
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Deserializes the config, removing each unknown key and trying again so every one
+/// of them is reported instead of only the first
+fn deserialize(table: &Spanned<DeTable<'_>>) -> Result<Config, Vec<Diagnostic>> {
+    let mut table = table.clone();
+    let mut diagnostics = Vec::new();
+    loop {
+        let e = match Config::deserialize(toml::Deserializer::from(table.clone())) {
+            Ok(config) if diagnostics.is_empty() => return Ok(config),
+            Ok(_) => break,
+            Err(e) => e,
+        };
+        diagnostics.push(toml_error(&e));
+        let removed = e.message().starts_with("unknown field")
+            && e.span()
+                .is_some_and(|span| remove_key(table.get_mut(), &span));
+        if !removed {
+            break;
+        }
+    }
+    // Keys are visited in sorted order, not in the order of the file
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.as_ref().map(|span| span.start));
+    Err(diagnostics)
+}
+
+/// Removes the key at `span` from `table` or any table nested in it
+fn remove_key(table: &mut DeTable<'_>, span: &Range<usize>) -> bool {
+    let key = table.keys().find(|key| key.span() == *span).cloned();
+    if let Some(key) = key {
+        table.remove(&key);
+        return true;
+    }
+    table
+        .iter_mut()
+        .any(|(_, value)| remove_nested_key(value.get_mut(), span))
+}
+
+fn remove_nested_key(value: &mut DeValue<'_>, span: &Range<usize>) -> bool {
+    match value {
+        DeValue::Table(table) => remove_key(table, span),
+        DeValue::Array(array) => array
+            .iter_mut()
+            .any(|entry| remove_nested_key(entry.get_mut(), span)),
+        _ => false,
+    }
+}
+
+fn toml_error(e: &toml::de::Error) -> Diagnostic {
+    Diagnostic {
+        span: e.span(),
+        message: match did_you_mean(e.message()) {
+            Some(hint) => format!("{}, {}", e.message().trim_end(), hint),
+            None => e.message().trim_end().to_string(),
+        },
+    }
+}
+
+fn with(path: &[Segment], segment: Segment) -> Vec<Segment> {
+    let mut path = path.to_vec();
+    path.push(segment);
+    path
+}
+
+/// Returns the span of the key or array entry at `path`, or of the closest parent
+/// that exists
+fn locate(table: &Spanned<DeTable<'_>>, path: &[Segment]) -> Option<Range<usize>> {
+    let mut span = None;
+    let mut value: Option<&DeValue<'_>> = None;
+    for segment in path {
+        let next = match (segment, value) {
+            (Segment::Key(key), None) => find_key(table.get_ref(), key),
+            (Segment::Key(key), Some(DeValue::Table(table))) => find_key(table, key),
+            (Segment::Index(i), Some(DeValue::Array(array))) => {
+                array.get(*i).map(|entry| (entry.span(), entry))
+            }
+            _ => None,
+        };
+        let Some((next_span, next)) = next else {
+            break;
+        };
+        span = Some(next_span);
+        value = Some(next.get_ref());
+    }
+    span
+}
+
+fn find_key<'a, 'i>(
+    table: &'a DeTable<'i>,
+    key: &str,
+) -> Option<(Range<usize>, &'a Spanned<DeValue<'i>>)> {
+    table
+        .iter()
+        .find(|(name, _)| name.get_ref() == key)
+        .map(|(name, value)| (name.span(), value))
+}
+
+/// Converts a byte offset into a 1-based line and column
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(contents: &str, path: &[Segment]) -> Option<(usize, usize)> {
+        let table = DeTable::parse(contents).unwrap();
+        locate(&table, path).map(|span| line_column(contents, span.start))
+    }
+
+    #[test]
+    fn line_column_is_one_based() {
+        let contents = "a = 1\nbb = 2\n";
+        assert_eq!(line_column(contents, 0), (1, 1));
+        assert_eq!(line_column(contents, 4), (1, 5));
+        assert_eq!(line_column(contents, 6), (2, 1));
+        assert_eq!(line_column(contents, 11), (2, 6));
+    }
+
+    #[test]
+    fn line_column_counts_characters_not_bytes() {
+        assert_eq!(line_column("ä = \"ö\"", "ä = ".len()), (1, 5));
+    }
+
+    #[test]
+    fn line_column_clamps_past_the_end() {
+        assert_eq!(line_column("a\nb", 100), (2, 2));
+    }
+
+    #[test]
+    fn locate_finds_nested_keys_and_entries() {
+        let contents = "port = 1\n\n[reconnect]\nmax = 5\n\n[layers.nav]\ncommands = [\"a\", { command = \"b\" }]\n";
+        let key = Segment::key;
+        assert_eq!(position(contents, &[key("port")]), Some((1, 1)));
+        assert_eq!(
+            position(contents, &[key("reconnect"), key("max")]),
+            Some((4, 1))
+        );
+        let commands = vec![key("layers"), key("nav"), key("commands")];
+        assert_eq!(
+            position(contents, &with(&commands, Segment::Index(0))),
+            Some((7, 13))
+        );
+        assert_eq!(
+            position(
+                contents,
+                &with(&with(&commands, Segment::Index(1)), key("command"))
+            ),
+            Some((7, 20))
+        );
+    }
+
+    #[test]
+    fn locate_finds_array_of_tables_keys() {
+        let contents = "[[kanata]]\nname = \"a\"\n\n[[kanata]]\nname = \"b\"\n";
+        let path = [
+            Segment::key("kanata"),
+            Segment::Index(1),
+            Segment::key("name"),
+        ];
+        assert_eq!(position(contents, &path), Some((5, 1)));
+    }
+
+    #[test]
+    fn locate_falls_back_to_the_closest_parent() {
+        let contents = "[reconnect]\nmax = 5\n";
+        let key = Segment::key;
+        assert_eq!(
+            position(contents, &[key("reconnect"), key("multiplier")]),
+            position(contents, &[key("reconnect")])
+        );
+        // Indexing into a table or past the end of an array stops at the parent
+        assert_eq!(
+            position(contents, &[key("reconnect"), Segment::Index(0)]),
+            position(contents, &[key("reconnect")])
+        );
+        assert_eq!(position(contents, &[key("port")]), None);
+        assert_eq!(position(contents, &[]), None);
+    }
+
+    #[test]
+    fn deserialize_reports_every_unknown_key_in_file_order() {
+        let contents = "port = 1\nscirpt_path = \"x\"\nlog_levl = \"info\"\n\
+                        [[rules]]\nto = \"a\"\non_entr = []\n";
+        let table = DeTable::parse(contents).unwrap();
+        let Err(diagnostics) = deserialize(&table) else {
+            panic!("unknown keys were accepted");
+        };
+        let reported: Vec<_> = diagnostics
+            .iter()
+            .map(|d| {
+                let (line, _) = line_column(contents, d.span.clone().unwrap().start);
+                (line, d.message.split(',').next().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            reported,
+            [
+                (2, "unknown field `scirpt_path`"),
+                (3, "unknown field `log_levl`"),
+                (6, "unknown field `on_entr`"),
+            ]
+        );
+        assert!(diagnostics[0]
+            .message
+            .ends_with("did you mean `script_path`?"));
+        assert!(diagnostics[2].message.ends_with("did you mean `on_enter`?"));
+    }
+
+    #[test]
+    fn deserialize_stops_at_other_errors() {
+        let contents = "log_levl = \"info\"\nport = \"x\"\n";
+        let table = DeTable::parse(contents).unwrap();
+        let Err(diagnostics) = deserialize(&table) else {
+            panic!("invalid config was accepted");
+        };
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0]
+            .message
+            .starts_with("unknown field `log_levl`"));
+        assert_eq!(
+            diagnostics[1].span,
+            table.get_ref().get("port").map(|v| v.span())
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use crate::addr::KanataAddr;
+use crate::backoff::Backoff;
+use crate::protocol::{ClientMessage, ServerMessage, ServerResponse};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something that happened on the client's connection to kanata
+#[derive(Debug)]
+pub enum Event {
+    /// A connection to kanata was established
+    Connected,
+    /// Kanata sent a message
+    Message(ServerMessage),
+    /// Connecting to kanata failed
+    ConnectFailed {
+        error: io::Error,
+        /// Delay before the next attempt, or `None` if the client gave up and
+        /// iteration ends
+        retry_in: Option<Duration>,
+    },
+    /// An established connection was lost
+    ///
+    /// A connection closed before kanata sent anything counts as a failed attempt.
+    Disconnected {
+        error: io::Error,
+        /// Delay before reconnecting, zero if kanata cleanly closed a connection it
+        /// had sent messages on, or `None` if the client gave up and iteration ends
+        retry_in: Option<Duration>,
+    },
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+/// Blocking client for kanata's TCP server
+///
+/// Iterating over the client yields [`Event`]s, connecting and reconnecting with
+/// [`Backoff`] as needed, until [`Backoff::max_attempts`] is exhausted.
+/// [`KanataClient::send`] can be called between events, e.g. on [`Event::Connected`].
+pub struct KanataClient {
+    addr: KanataAddr,
+    conn: Option<Connection>,
+    backoff: Backoff,
+    /// Consecutive failed connection attempts, reset once kanata sends a message
+    failures: u32,
+    /// Whether kanata sent a message on the current connection
+    received: bool,
+    /// Delay before the next connection attempt, `None` once the client gave up
+    retry_in: Option<Duration>,
+    /// The current connection's stream, shared with [`DisconnectHandle`]s
+    current: Arc<Mutex<Option<TcpStream>>>,
+}
+
+/// Closes a [`KanataClient`]'s connection from another thread
+///
+/// A client blocked waiting for kanata yields [`Event::Disconnected`] and reconnects
+/// right away, e.g. after [`KanataClient::set_addr`] was called.
+#[derive(Debug, Clone)]
+pub struct DisconnectHandle {
+    current: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl DisconnectHandle {
+    /// Closes the current connection, if any
+    pub fn disconnect(&self) {
+        let current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(stream) = current.as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl KanataClient {
+    /// Creates a client for `addr` without connecting; iterating connects lazily
+    pub fn new(addr: impl Into<KanataAddr>) -> Self {
+        KanataClient {
+            addr: addr.into(),
+            conn: None,
+            backoff: Backoff::default(),
+            failures: 0,
+            received: false,
+            retry_in: Some(Duration::ZERO),
+            current: Arc::default(),
+        }
+    }
+
+    /// Creates a client for `addr` and connects immediately
+    pub fn connect(addr: impl Into<KanataAddr>) -> io::Result<Self> {
+        let mut client = KanataClient::new(addr);
+        client.open()?;
+        Ok(client)
+    }
+
+    /// Sets how long to wait before reconnecting after a failure
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Replaces the backoff for future reconnects
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    pub fn addr(&self) -> &KanataAddr {
+        &self.addr
+    }
+
+    /// Switches to `addr`, dropping the current connection so that iterating
+    /// connects to the new address right away
+    pub fn set_addr(&mut self, addr: impl Into<KanataAddr>) {
+        self.addr = addr.into();
+        self.close();
+        self.failures = 0;
+        self.retry_in = Some(Duration::ZERO);
+    }
+
+    /// Returns a handle to close the connection from another thread
+    pub fn disconnect_handle(&self) -> DisconnectHandle {
+        DisconnectHandle {
+            current: Arc::clone(&self.current),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Sets the read timeout of the current connection
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.connection()?.writer.set_read_timeout(timeout)
+    }
+
+    /// Sends `msg` to kanata
+    pub fn send(&mut self, msg: &ClientMessage) -> io::Result<()> {
+        let line = msg.to_line()?;
+        self.connection_mut()?.writer.write_all(line.as_bytes())
+    }
+
+    /// Blocks until kanata sends a [`ServerMessage`], skipping any other lines
+    pub fn recv(&mut self) -> io::Result<ServerMessage> {
+        loop {
+            let line = self.read_line()?;
+            match serde_json::from_str::<ServerMessage>(&line) {
+                Ok(msg) => return Ok(msg),
+                Err(e) => log::trace!("ignoring line {:?}: {}", line.trim_end(), e),
+            }
+        }
+    }
+
+    /// Sends `msg` and blocks until kanata responds to it
+    ///
+    /// Kanata broadcasts messages such as layer changes to every client, so anything
+    /// other than a [`ServerResponse`] or [`ServerMessage::Error`] is skipped.
+    pub fn request(&mut self, msg: &ClientMessage) -> io::Result<ServerResponse> {
+        self.send(msg)?;
+        loop {
+            let line = self.read_line()?;
+            if let Ok(response) = serde_json::from_str::<ServerResponse>(&line) {
+                return Ok(response);
+            }
+            if let Ok(ServerMessage::Error { msg }) = serde_json::from_str(&line) {
+                return Ok(ServerResponse::Error { msg });
+            }
+            log::trace!("skipping unrelated line: {}", line.trim_end());
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let stream = self.connect_any()?;
+        *self.current.lock().unwrap_or_else(|e| e.into_inner()) = Some(stream.try_clone()?);
+        self.conn = Some(Connection {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        });
+        Ok(())
+    }
+
+    /// Tries every address the host resolves to, returning the first that connects
+    fn connect_any(&self) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.addr.resolve()? {
+            log::debug!("connecting to {}", addr);
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("resolve returns at least one address"))
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        let bytes_read = self.connection_mut()?.reader.read_line(&mut line)?;
+
+        // Connection closed
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by kanata",
+            ));
+        }
+
+        log::trace!("received: {}", line.trim_end());
+        Ok(line)
+    }
+
+    fn close(&mut self) {
+        self.conn = None;
+        self.received = false;
+        *self.current.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Counts a failed attempt, returning the delay before the next one or `None` if
+    /// the client gives up
+    fn fail(&mut self) -> Option<Duration> {
+        self.failures = self.failures.saturating_add(1);
+        self.retry_in = if self.backoff.exhausted(self.failures) {
+            None
+        } else {
+            Some(self.backoff.delay(self.failures))
+        };
+        self.retry_in
+    }
+
+    fn connection(&self) -> io::Result<&Connection> {
+        self.conn.as_ref().ok_or_else(not_connected)
+    }
+
+    fn connection_mut(&mut self) -> io::Result<&mut Connection> {
+        self.conn.as_mut().ok_or_else(not_connected)
+    }
+}
+
+impl Iterator for KanataClient {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if self.conn.is_none() {
+            let delay = self.retry_in?;
+            if !delay.is_zero() {
+                std::thread::sleep(delay);
+            }
+
+            log::info!("attempting to connect to kanata at {}", self.addr);
+            return Some(match self.open() {
+                Ok(()) => Event::Connected,
+                Err(error) => Event::ConnectFailed {
+                    error,
+                    retry_in: self.fail(),
+                },
+            });
+        }
+
+        Some(match self.recv() {
+            Ok(msg) => {
+                self.received = true;
+                self.failures = 0;
+                Event::Message(msg)
+            }
+            Err(error) => {
+                let received = self.received;
+                self.close();
+                // A clean close usually means kanata is restarting, so reconnect right away,
+                // but a peer that closes before sending anything, e.g. a port forward with
+                // kanata down, is retried like a failed connection attempt
+                let retry_in = if received && error.kind() == io::ErrorKind::UnexpectedEof {
+                    self.retry_in = Some(Duration::ZERO);
+                    self.retry_in
+                } else {
+                    self.fail()
+                };
+                Event::Disconnected { error, retry_in }
+            }
+        })
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "not connected to kanata")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Accepts connections forever, writing `lines` on each before closing it
+    fn server(lines: &'static str) -> KanataAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.write_all(lines.as_bytes());
+            }
+        });
+        addr.into()
+    }
+
+    fn client(addr: KanataAddr, max_attempts: u32) -> KanataClient {
+        KanataClient::new(addr).with_backoff(Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(10),
+            multiplier: 1.0,
+            max_attempts: Some(max_attempts),
+        })
+    }
+
+    #[test]
+    fn receives_messages() {
+        let addr = server("not json\n{\"LayerChange\":{\"new\":\"nav\"}}\n");
+        let mut client = client(addr, 1);
+        assert!(matches!(client.next(), Some(Event::Connected)));
+        assert!(matches!(
+            client.next(),
+            Some(Event::Message(ServerMessage::LayerChange { new })) if new == "nav"
+        ));
+    }
+
+    #[test]
+    fn reconnects_right_away_after_kanata_closes_a_working_connection() {
+        let addr = server("{\"LayerChange\":{\"new\":\"nav\"}}\n");
+        let mut client = client(addr, 1);
+        for _ in 0..3 {
+            assert!(matches!(client.next(), Some(Event::Connected)));
+            assert!(matches!(client.next(), Some(Event::Message(_))));
+            assert!(matches!(
+                client.next(),
+                Some(Event::Disconnected { retry_in: Some(delay), .. }) if delay.is_zero()
+            ));
+        }
+    }
+
+    #[test]
+    fn connections_closed_without_messages_count_as_failures() {
+        let mut client = client(server(""), 3);
+        for retry in [true, true, false] {
+            assert!(matches!(client.next(), Some(Event::Connected)));
+            match client.next() {
+                Some(Event::Disconnected { retry_in, .. }) => {
+                    assert_eq!(retry_in.is_some(), retry);
+                    assert!(retry_in.is_none_or(|delay| !delay.is_zero()));
+                }
+                other => panic!("expected a disconnect, got {:?}", other),
+            }
+        }
+        assert!(client.next().is_none());
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        // Nothing listens on a port that was just released
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut client = client(addr.into(), 2);
+        assert!(matches!(
+            client.next(),
+            Some(Event::ConnectFailed {
+                retry_in: Some(_),
+                ..
+            })
+        ));
+        assert!(matches!(
+            client.next(),
+            Some(Event::ConnectFailed { retry_in: None, .. })
+        ));
+        assert!(client.next().is_none());
+    }
+}
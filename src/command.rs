@@ -0,0 +1,362 @@
+use crate::config::{ActionConfig, CommandConfig, FileFormat, OnError};
+use crate::event::EventContext;
+use crate::script::{Task, TaskKind};
+use anyhow::{bail, Context};
+
+/// An entry of `commands`, `on_enter` or `on_exit`, compiled from an [`ActionConfig`]
+#[derive(Debug, Clone)]
+pub struct Action {
+    kind: ActionKind,
+    on_error: OnError,
+}
+
+#[derive(Debug, Clone)]
+enum ActionKind {
+    Command(Command),
+    Group {
+        actions: Vec<Action>,
+        parallel: bool,
+    },
+    WriteFile {
+        path: Template,
+        format: FileFormat,
+    },
+}
+
+impl Action {
+    pub fn new(action: &ActionConfig) -> anyhow::Result<Self> {
+        let table = match action {
+            ActionConfig::Command(command) => return Ok(Action::command(Command::new(command)?)),
+            ActionConfig::Table(table) => table,
+        };
+        let kinds = [
+            table.command.is_some(),
+            table.commands.is_some(),
+            table.write_file.is_some(),
+        ];
+        match kinds.iter().filter(|&&set| set).count() {
+            0 => bail!("one of `command`, `commands` and `write_file` is required"),
+            1 => {}
+            _ => bail!("only one of `command`, `commands` and `write_file` can be set"),
+        }
+        if table.parallel && table.commands.is_none() {
+            bail!("`parallel` needs `commands`");
+        }
+        if table.format.is_some() && table.write_file.is_none() {
+            bail!("`format` needs `write_file`");
+        }
+
+        let kind = if let Some(command) = &table.command {
+            ActionKind::Command(Command::new(command)?)
+        } else if let Some(actions) = &table.commands {
+            ActionKind::Group {
+                actions: Action::all(actions)?,
+                parallel: table.parallel,
+            }
+        } else {
+            let path = table.write_file.as_deref().unwrap_or_default();
+            if path.is_empty() {
+                bail!("`write_file` must not be empty");
+            }
+            ActionKind::WriteFile {
+                path: Template::parse(path).context("invalid `write_file`")?,
+                format: table.format.unwrap_or_default(),
+            }
+        };
+        Ok(Action {
+            kind,
+            on_error: table.on_error,
+        })
+    }
+
+    /// Compiles a list of actions
+    pub fn all(actions: &[ActionConfig]) -> anyhow::Result<Vec<Self>> {
+        actions
+            .iter()
+            .enumerate()
+            .map(|(i, action)| Action::new(action).with_context(|| format!("entry {}", i + 1)))
+            .collect()
+    }
+
+    /// A single command that doesn't stop the following ones if it fails
+    pub fn command(command: Command) -> Self {
+        Action {
+            kind: ActionKind::Command(command),
+            on_error: OnError::Continue,
+        }
+    }
+
+    /// Fills in the placeholders for `context`, `script_args` being passed to scripts only
+    pub fn task(&self, script_args: &[&str], context: &EventContext) -> Task {
+        let kind = match &self.kind {
+            ActionKind::Command(command) => {
+                let mut argv = command.argv(script_args, context).into_iter();
+                TaskKind::Script {
+                    // Command::new rejects empty commands
+                    path: argv.next().expect("empty command"),
+                    args: argv.collect(),
+                }
+            }
+            ActionKind::Group { actions, parallel } => TaskKind::Group {
+                tasks: actions
+                    .iter()
+                    .map(|action| action.task(script_args, context))
+                    .collect(),
+                parallel: *parallel,
+            },
+            ActionKind::WriteFile { path, format } => TaskKind::WriteFile {
+                path: path.expand(context),
+                contents: match format {
+                    FileFormat::Text => {
+                        format!("{}\n", context.layer.as_deref().unwrap_or_default())
+                    }
+                    FileFormat::Json => format!("{}\n", context.to_json()),
+                },
+            },
+        };
+        Task {
+            kind,
+            stop_on_error: self.on_error == OnError::Stop,
+        }
+    }
+}
+
+/// A command to run for an event, compiled from a [`CommandConfig`]
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Script called with the layer as `$1` and the previous layer as `$2`
+    Script(String),
+    /// Program and arguments with placeholders, called without implicit arguments
+    Argv(Vec<Template>),
+}
+
+impl Command {
+    pub fn new(command: &CommandConfig) -> anyhow::Result<Self> {
+        match command {
+            CommandConfig::Script(script_path) => Ok(Command::Script(script_path.clone())),
+            CommandConfig::Argv(argv) => {
+                if argv.is_empty() {
+                    bail!("command must not be empty");
+                }
+                let argv = argv
+                    .iter()
+                    .map(|arg| Template::parse(arg))
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Command::Argv(argv))
+            }
+        }
+    }
+
+    /// Returns the program and its arguments for `context`, `script_args` being
+    /// passed to scripts only
+    pub fn argv(&self, script_args: &[&str], context: &EventContext) -> Vec<String> {
+        match self {
+            Command::Script(script_path) => std::iter::once(script_path.clone())
+                .chain(script_args.iter().map(|arg| arg.to_string()))
+                .collect(),
+            Command::Argv(argv) => argv.iter().map(|arg| arg.expand(context)).collect(),
+        }
+    }
+}
+
+/// Values that can be substituted into a [`Template`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    /// The new layer for layer events, the current layer otherwise
+    Layer,
+    /// The previous layer, empty if unknown
+    Prev,
+    Instance,
+    /// The layer's `display_name`, falling back to the layer name
+    DisplayName,
+    /// Position of the layer in kanata's layer list, empty if unknown
+    Index,
+}
+
+impl Placeholder {
+    const ALL: [(&'static str, Placeholder); 5] = [
+        ("layer", Placeholder::Layer),
+        ("prev", Placeholder::Prev),
+        ("instance", Placeholder::Instance),
+        ("display_name", Placeholder::DisplayName),
+        ("index", Placeholder::Index),
+    ];
+
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        Placeholder::ALL
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, placeholder)| *placeholder)
+            .with_context(|| {
+                let known: Vec<_> = Placeholder::ALL
+                    .iter()
+                    .map(|(name, _)| format!("{{{}}}", name))
+                    .collect();
+                format!(
+                    "unknown placeholder {{{}}}, expected one of {}",
+                    name,
+                    known.join(", ")
+                )
+            })
+    }
+
+    fn value(self, context: &EventContext) -> String {
+        let layer = context.layer.clone().unwrap_or_default();
+        match self {
+            Placeholder::Layer => layer,
+            Placeholder::Prev => context.prev_layer.clone().unwrap_or_default(),
+            Placeholder::Instance => context.instance.clone(),
+            Placeholder::DisplayName => context.display_name.clone().unwrap_or(layer),
+            Placeholder::Index => context
+                .layer_index
+                .map(|index| index.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A command argument with `{placeholder}`s; `{{` and `}}` are literal braces
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        bail!("unclosed {{ in {:?}", template);
+                    };
+                    let placeholder = Placeholder::parse(&rest[..end])
+                        .with_context(|| format!("invalid argument {:?}", template))?;
+                    chars = rest[end + 1..].chars();
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                }
+                '}' => bail!(
+                    "unmatched }} in {:?}, use }}}} for a literal brace",
+                    template
+                ),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn expand(&self, context: &EventContext) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Placeholder(placeholder) => placeholder.value(context),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> EventContext {
+        EventContext {
+            event: "layer_change",
+            instance: "laptop".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 1,
+            timestamp: 0,
+            layer: Some("nav".to_string()),
+            display_name: None,
+            prev_layer: None,
+            layer_index: Some(2),
+            layer_count: Some(3),
+            config_path: None,
+        }
+    }
+
+    fn expand(template: &str) -> String {
+        Template::parse(template).unwrap().expand(&context())
+    }
+
+    fn error(template: &str) -> String {
+        format!("{:#}", Template::parse(template).unwrap_err())
+    }
+
+    #[test]
+    fn expand_substitutes_placeholders() {
+        assert_eq!(expand("label={layer}"), "label=nav");
+        assert_eq!(expand("{instance}:{index}{layer}"), "laptop:2nav");
+        assert_eq!(expand("plain"), "plain");
+        assert_eq!(expand(""), "");
+    }
+
+    #[test]
+    fn expand_falls_back_for_missing_values() {
+        // display_name falls back to the layer, prev is empty when unknown
+        assert_eq!(expand("{display_name}|{prev}|"), "nav||");
+        let mut context = context();
+        context.layer = None;
+        context.layer_index = None;
+        let template = Template::parse("[{layer}][{index}][{display_name}]").unwrap();
+        assert_eq!(template.expand(&context), "[][][]");
+    }
+
+    #[test]
+    fn double_braces_are_literal() {
+        assert_eq!(expand("{{layer}}"), "{layer}");
+        assert_eq!(expand("{{{layer}}}"), "{nav}");
+        assert_eq!(expand("}}{{"), "}{");
+        assert_eq!(expand("{{}}ä{layer}"), "{}änav");
+    }
+
+    #[test]
+    fn unclosed_braces_are_rejected() {
+        assert_eq!(error("label={layer"), "unclosed { in \"label={layer\"");
+        assert_eq!(error("{"), "unclosed { in \"{\"");
+        assert_eq!(error("{{{"), "unclosed { in \"{{{\"");
+    }
+
+    #[test]
+    fn unmatched_closing_braces_are_rejected() {
+        let message = "unmatched } in \"{layer}}\", use }} for a literal brace";
+        assert_eq!(error("{layer}}"), message);
+        assert!(error("a}b").starts_with("unmatched } in \"a}b\""));
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        assert_eq!(
+            error("{lyer}"),
+            "invalid argument \"{lyer}\": unknown placeholder {lyer}, expected one of \
+             {layer}, {prev}, {instance}, {display_name}, {index}"
+        );
+        assert!(error("{}").contains("unknown placeholder {},"));
+        // Placeholders don't nest
+        assert!(error("{la{yer}}").contains("unknown placeholder {la{yer},"));
+    }
+}
@@ -0,0 +1,1308 @@
+use crate::command::{Action, Command};
+use crate::layers::LayerHandlers;
+use crate::script::json_to_string;
+use anyhow::{anyhow, bail, Context};
+use indexmap::IndexMap;
+use kanata_layer_observer::{Backoff, KanataAddr};
+use serde::de::{self, value::MapAccessDeserializer, value::SeqAccessDeserializer};
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Host that kanata's TCP server is listening on: an IPv4/IPv6 address or hostname
+    #[serde(default = "default_host")]
+    pub host: String,
+
+    /// Port that kanata's TCP server is listening on, required unless every
+    /// `[[kanata]]` instance sets its own
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Path to the script to execute on layer change, unless a `[layers]` table matches
+    #[serde(default)]
+    pub script_path: Option<String>,
+
+    /// Program and arguments to execute on layer change instead of `script_path`,
+    /// with placeholders such as `{layer}`
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+
+    /// Scripts for specific layers, keyed by layer name, glob or `re:` regex
+    #[serde(default)]
+    pub layers: IndexMap<String, LayerConfig>,
+
+    /// Scripts to run when entering and leaving layers
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+
+    /// Path to the script to execute when kanata reloads its config
+    #[serde(default)]
+    pub on_config_reload: Option<String>,
+
+    /// Scripts to execute when kanata pushes a message via `push-msg`
+    #[serde(default)]
+    pub message_push: MessagePushConfig,
+
+    /// Log level: "off", "error", "warn", "info", "debug", or "trace"
+    #[serde(default)]
+    pub log_level: LogLevel,
+
+    /// Seconds after which a running script is killed, 0 to let scripts run forever
+    #[serde(default = "default_script_timeout")]
+    pub script_timeout: f64,
+
+    /// Pass the event as JSON on the stdin of every script
+    #[serde(default)]
+    pub stdin_json: bool,
+
+    /// Only run layer scripts once the layer has been stable for this many milliseconds
+    #[serde(default)]
+    pub debounce_ms: u64,
+
+    /// Kill running scripts and skip queued ones when a layer change fires, after
+    /// debouncing
+    #[serde(default)]
+    pub latest_wins: bool,
+
+    /// Backoff between attempts to reconnect to kanata
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+
+    /// Where to re-broadcast kanata's messages to local subscribers
+    #[serde(default)]
+    pub broadcast: Option<BroadcastConfig>,
+
+    /// Kanata instances to observe; if empty, the top-level host and port are used
+    #[serde(default)]
+    pub kanata: Vec<InstanceConfig>,
+}
+
+/// A `[layers.<pattern>]` table
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerConfig {
+    /// Commands to execute, in order, instead of `script_path`
+    #[serde(default)]
+    pub commands: Vec<ActionConfig>,
+
+    /// Name substituted for `{display_name}`, defaults to the layer name
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+/// An entry of `commands`, `on_enter` or `on_exit`, see [`crate::command::Action`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionConfig {
+    Command(CommandConfig),
+    Table(ActionTableConfig),
+}
+
+/// A script or program to execute, see [`Command`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandConfig {
+    /// Path to a script, called with the layer and previous layer as arguments
+    Script(String),
+    /// Program and arguments with placeholders
+    Argv(Vec<String>),
+}
+
+// Picked by the TOML type instead of with #[serde(untagged)], which would hide
+// errors inside action tables, such as unknown keys, behind "did not match any variant"
+impl<'de> Deserialize<'de> for ActionConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = ActionConfig;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a script path, an array of program and arguments, or a table")
+            }
+
+            fn visit_str<E: de::Error>(self, script_path: &str) -> Result<Self::Value, E> {
+                CommandVisitor
+                    .visit_str(script_path)
+                    .map(ActionConfig::Command)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, argv: A) -> Result<Self::Value, A::Error> {
+                CommandVisitor.visit_seq(argv).map(ActionConfig::Command)
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, table: A) -> Result<Self::Value, A::Error> {
+                ActionTableConfig::deserialize(MapAccessDeserializer::new(table))
+                    .map(ActionConfig::Table)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(CommandVisitor)
+    }
+}
+
+struct CommandVisitor;
+
+impl<'de> de::Visitor<'de> for CommandVisitor {
+    type Value = CommandConfig;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a script path or an array of program and arguments")
+    }
+
+    fn visit_str<E: de::Error>(self, script_path: &str) -> Result<Self::Value, E> {
+        Ok(CommandConfig::Script(script_path.to_string()))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, argv: A) -> Result<Self::Value, A::Error> {
+        Vec::deserialize(SeqAccessDeserializer::new(argv)).map(CommandConfig::Argv)
+    }
+}
+
+/// An action written as a table: a single `command`, a group of `commands`, or a
+/// file to write
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionTableConfig {
+    #[serde(default)]
+    pub command: Option<CommandConfig>,
+
+    /// Actions executed as a group
+    #[serde(default)]
+    pub commands: Option<Vec<ActionConfig>>,
+
+    /// Path of a file to replace with the current layer, with placeholders
+    #[serde(default)]
+    pub write_file: Option<String>,
+
+    /// What to write to `write_file`, "text" if unset
+    #[serde(default)]
+    pub format: Option<FileFormat>,
+
+    /// Start all of `commands` at once instead of one after another
+    #[serde(default)]
+    pub parallel: bool,
+
+    /// What to do with the following actions if this one fails
+    #[serde(default)]
+    pub on_error: OnError,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// The layer name followed by a newline
+    #[default]
+    Text,
+    /// The event as a JSON object, like with `stdin_json`
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Run the following actions anyway
+    #[default]
+    Continue,
+    /// Skip the following actions of the same list
+    Stop,
+}
+
+/// A `log_level`, matched case-insensitively
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    const ALL: [(&'static str, LogLevel); 6] = [
+        ("off", LogLevel::Off),
+        ("error", LogLevel::Error),
+        ("warn", LogLevel::Warn),
+        ("info", LogLevel::Info),
+        ("debug", LogLevel::Debug),
+        ("trace", LogLevel::Trace),
+    ];
+
+    pub fn filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl TryFrom<String> for LogLevel {
+    type Error = String;
+
+    fn try_from(level: String) -> Result<Self, String> {
+        LogLevel::ALL
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&level))
+            .map(|(_, level)| *level)
+            .ok_or_else(|| {
+                let known: Vec<_> = LogLevel::ALL
+                    .iter()
+                    .map(|(name, _)| format!("`{}`", name))
+                    .collect();
+                format!(
+                    "unknown log level `{}`, expected one of {}",
+                    level,
+                    known.join(", ")
+                )
+            })
+    }
+}
+
+/// A `[[rules]]` entry, see [`crate::layers::Rule`]
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    /// Pattern the previous layer must match, any layer if unset
+    #[serde(default)]
+    pub from: Option<String>,
+
+    /// Pattern the new layer must match and keep matching for the rule to stay
+    /// active; if unset, the rule is entered on every change from `from`
+    #[serde(default)]
+    pub to: Option<String>,
+
+    /// Commands to execute when the rule is entered
+    #[serde(default)]
+    pub on_enter: Vec<ActionConfig>,
+
+    /// Commands to execute when the layer no longer matches `to`
+    #[serde(default)]
+    pub on_exit: Vec<ActionConfig>,
+}
+
+/// A `[[kanata]]` entry; unset values fall back to the top-level ones
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceConfig {
+    /// Name passed to scripts in `KANATA_INSTANCE`
+    pub name: String,
+
+    #[serde(default)]
+    pub host: Option<String>,
+
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    #[serde(default)]
+    pub script_path: Option<String>,
+
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub on_config_reload: Option<String>,
+
+    #[serde(default)]
+    pub broadcast: Option<BroadcastConfig>,
+}
+
+/// A `[broadcast]` table
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BroadcastConfig {
+    /// Path of a Unix domain socket to listen on
+    #[serde(default)]
+    pub socket: Option<String>,
+
+    /// Port to listen on at 127.0.0.1
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+/// A kanata instance to observe, with top-level defaults filled in
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub name: String,
+    pub addr: KanataAddr,
+    /// Commands for layers without a matching `[layers]` table
+    pub commands: Vec<Action>,
+    pub on_config_reload: Option<String>,
+    pub broadcast: Option<BroadcastConfig>,
+}
+
+/// A step in the path from the root table to a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl Segment {
+    pub fn key(key: &str) -> Self {
+        Segment::Key(key.to_string())
+    }
+}
+
+/// A value that parses fine but can't be used, with the path of its key
+#[derive(Debug)]
+pub struct InvalidValue {
+    pub path: Vec<Segment>,
+    pub error: anyhow::Error,
+}
+
+impl InvalidValue {
+    fn new(path: Vec<Segment>, error: anyhow::Error) -> Self {
+        InvalidValue { path, error }
+    }
+}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
+/// Instance name used when no `[[kanata]]` entries are configured
+pub const DEFAULT_INSTANCE: &str = "default";
+
+/// How similar an unknown key or value must be to a known one to suggest it
+const SUGGESTION_THRESHOLD: f64 = 0.8;
+
+/// Prefix of the environment variables that override config keys
+pub const ENV_PREFIX: &str = "KANATA_OBSERVER_";
+
+/// Tables keyed by names from the config file, such as layer names, rather than by
+/// fields, so their keys keep their case in environment variables
+const NAMED_TABLES: [&[&str]; 2] = [&["layers"], &["message_push", "routes"]];
+
+impl Config {
+    /// Parses a config file with `overrides` from [`env_overrides`] applied on top,
+    /// suggesting the closest match for unknown keys and values
+    pub fn parse(contents: &str, overrides: &[(String, String)]) -> anyhow::Result<Self> {
+        // Deserializing the file on its own first keeps line and column in its errors
+        let config = toml::from_str(contents).map_err(suggest)?;
+        if overrides.is_empty() {
+            return Ok(config);
+        }
+
+        let mut config = toml::Value::Table(toml::from_str(contents)?);
+        // The override that made the config invalid, unless a later one fixed it
+        let mut broken = None;
+        for (name, value) in overrides {
+            let path = env_path(&name[ENV_PREFIX.len()..]);
+            let valid = set_env_key(&mut config, &path, value)
+                .with_context(|| format!("invalid {}", name))?;
+            if valid {
+                broken = None;
+            } else if broken.is_none() {
+                broken = Some(name);
+            }
+        }
+        config
+            .try_into()
+            .map_err(suggest)
+            .with_context(|| format!("invalid {}", broken.map_or("overrides", String::as_str)))
+    }
+
+    /// Checks values that parse fine but can't be used
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.validate_options()?;
+        self.layer_handlers()?;
+        Ok(())
+    }
+
+    /// Like [`Config::validate`], without compiling `[layers]` and `[[rules]]`
+    pub fn validate_options(&self) -> Result<(), InvalidValue> {
+        if Duration::try_from_secs_f64(self.script_timeout).is_err() {
+            return Err(InvalidValue::new(
+                vec![Segment::key("script_timeout")],
+                anyhow!("invalid script_timeout = {}", self.script_timeout),
+            ));
+        }
+        self.reconnect.to_backoff()?;
+
+        let top_level = (vec![Segment::key("broadcast")], &self.broadcast);
+        let instances = self.kanata.iter().enumerate().map(|(i, instance)| {
+            let path = vec![
+                Segment::key("kanata"),
+                Segment::Index(i),
+                Segment::key("broadcast"),
+            ];
+            (path, &instance.broadcast)
+        });
+        for (path, broadcast) in std::iter::once(top_level).chain(instances) {
+            if let Some(broadcast) = broadcast {
+                if broadcast.socket.is_none() && broadcast.port.is_none() {
+                    return Err(InvalidValue::new(
+                        path,
+                        anyhow!("[broadcast] needs a `socket` or `port`"),
+                    ));
+                }
+            }
+        }
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.to.is_none() && !rule.on_exit.is_empty() {
+                return Err(InvalidValue::new(
+                    vec![
+                        Segment::key("rules"),
+                        Segment::Index(i),
+                        Segment::key("on_exit"),
+                    ],
+                    anyhow!("`on_exit` needs `to`, a rule without it is left right away"),
+                ));
+            }
+        }
+
+        self.instances()?;
+        Ok(())
+    }
+
+    /// `script_timeout` as a duration, `None` if disabled
+    pub fn script_timeout(&self) -> Option<Duration> {
+        Duration::try_from_secs_f64(self.script_timeout)
+            .ok()
+            .filter(|timeout| !timeout.is_zero())
+    }
+
+    pub fn layer_handlers(&self) -> anyhow::Result<LayerHandlers> {
+        LayerHandlers::new(&self.layers, &self.rules)
+    }
+
+    /// Returns the kanata instances to observe
+    pub fn instances(&self) -> Result<Vec<Instance>, InvalidValue> {
+        let top_level_command = || {
+            layer_command(&self.script_path, &self.command)
+                .map_err(|e| InvalidValue::new(vec![Segment::key("command")], e))
+        };
+        if self.kanata.is_empty() {
+            let port = self.port.ok_or_else(|| {
+                InvalidValue::new(
+                    vec![Segment::key("port")],
+                    anyhow!("`port` is required unless [[kanata]] instances are configured"),
+                )
+            })?;
+            return Ok(vec![Instance {
+                name: DEFAULT_INSTANCE.to_string(),
+                addr: KanataAddr::new(self.host.clone(), port),
+                commands: top_level_command()?,
+                on_config_reload: self.on_config_reload.clone(),
+                broadcast: self.broadcast.clone(),
+            }]);
+        }
+
+        let mut names = HashSet::new();
+        let mut sockets = HashSet::new();
+        let mut ports = HashSet::new();
+        self.kanata
+            .iter()
+            .enumerate()
+            .map(|(i, instance)| {
+                let path = |key: &str| {
+                    vec![Segment::key("kanata"), Segment::Index(i), Segment::key(key)]
+                };
+                if !names.insert(instance.name.as_str()) {
+                    return Err(InvalidValue::new(
+                        path("name"),
+                        anyhow!("duplicate [[kanata]] instance name {:?}", instance.name),
+                    ));
+                }
+                let port = instance.port.or(self.port).ok_or_else(|| {
+                    InvalidValue::new(
+                        path("port"),
+                        anyhow!("[[kanata]] instance {:?} has no `port`", instance.name),
+                    )
+                })?;
+                let host = instance.host.as_ref().unwrap_or(&self.host);
+                // The instance's own script_path or command replaces both top-level ones
+                let mut commands = layer_command(&instance.script_path, &instance.command)
+                    .with_context(|| format!("invalid [[kanata]] instance {:?}", instance.name))
+                    .map_err(|e| InvalidValue::new(path("command"), e))?;
+                if commands.is_empty() {
+                    commands = top_level_command()?;
+                }
+
+                let broadcast = instance.broadcast.as_ref().or(self.broadcast.as_ref());
+                if let Some(broadcast) = broadcast {
+                    let socket_taken = broadcast
+                        .socket
+                        .as_ref()
+                        .is_some_and(|socket| !sockets.insert(socket));
+                    let port_taken = broadcast.port.is_some_and(|port| !ports.insert(port));
+                    if socket_taken || port_taken {
+                        return Err(InvalidValue::new(
+                            path("broadcast"),
+                            anyhow!(
+                                "[[kanata]] instance {:?} broadcasts on the same socket or port as another instance, give each its own [kanata.broadcast]",
+                                instance.name
+                            ),
+                        ));
+                    }
+                }
+
+                Ok(Instance {
+                    name: instance.name.clone(),
+                    addr: KanataAddr::new(host.clone(), port),
+                    commands,
+                    on_config_reload: instance
+                        .on_config_reload
+                        .clone()
+                        .or(self.on_config_reload.clone()),
+                    broadcast: broadcast.cloned(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Suggests the closest expected name for an "unknown field `x`, expected one of ..."
+/// error, the format serde uses for unknown fields and variants
+pub fn did_you_mean(message: &str) -> Option<String> {
+    let (unknown, expected) = message
+        .strip_prefix("unknown ")?
+        .split_once(", expected ")?;
+    let unknown = unknown.split('`').nth(1)?;
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|name| (strsim::jaro_winkler(unknown, name), name))
+        .filter(|(similarity, _)| *similarity >= SUGGESTION_THRESHOLD)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, name)| format!("did you mean `{}`?", name))
+}
+
+fn suggest(e: toml::de::Error) -> anyhow::Error {
+    match did_you_mean(e.message()) {
+        Some(hint) => anyhow::anyhow!("{}\n{}", e.to_string().trim_end(), hint),
+        None => e.into(),
+    }
+}
+
+/// Returns the `KANATA_OBSERVER_*` environment variables, sorted by name
+///
+/// `__` separates nested keys and array indexes, e.g. `KANATA_OBSERVER_RECONNECT__MAX`
+/// for `max` in `[reconnect]` and `KANATA_OBSERVER_KANATA__0__PORT` for the `port` of
+/// the first `[[kanata]]` entry.
+pub fn env_overrides() -> Vec<(String, String)> {
+    let mut overrides: Vec<_> = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+/// Splits an environment variable name without [`ENV_PREFIX`] into the path of the
+/// key it sets, lowercasing field names but not the keys of [`NAMED_TABLES`]
+fn env_path(key: &str) -> Vec<String> {
+    let mut path: Vec<String> = Vec::new();
+    for segment in key.split("__") {
+        let parent = path.iter().map(String::as_str);
+        let named = NAMED_TABLES
+            .iter()
+            .any(|table| parent.clone().eq(table.iter().copied()));
+        path.push(if named {
+            segment.to_string()
+        } else {
+            segment.to_lowercase()
+        });
+    }
+    path
+}
+
+/// Parses an environment variable as a TOML value, e.g. `1012`, `true` or
+/// `["echo", "{layer}"]`, falling back to a plain string; empty unsets the key
+fn env_value(value: &str) -> Option<toml::Value> {
+    if value.is_empty() {
+        return None;
+    }
+    let value = toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    Some(value)
+}
+
+/// Sets the key at `path` to the value of an environment variable, returning whether
+/// the config is still valid
+///
+/// A value that parses as another TOML type is set as a plain string instead when the
+/// key doesn't accept that type, e.g. for `KANATA_OBSERVER_SCRIPT_PATH=true`.
+fn set_env_key(config: &mut toml::Value, path: &[String], value: &str) -> anyhow::Result<bool> {
+    let new = env_value(value);
+    let string = new
+        .as_ref()
+        .filter(|new| !new.is_str())
+        .map(|_| (config.clone(), toml::Value::String(value.to_string())));
+    set_key(config, path, new)?;
+    let error = match config.clone().try_into::<Config>() {
+        Ok(_) => return Ok(true),
+        Err(e) => e,
+    };
+    let Some((mut string_config, string)) = string else {
+        return Ok(false);
+    };
+    if !error.message().starts_with("invalid type") {
+        return Ok(false);
+    }
+    set_key(&mut string_config, path, Some(string))?;
+    match string_config.clone().try_into::<Config>() {
+        // The error is about another key, so this one didn't need a string
+        Err(e) if e.message() == error.message() => Ok(false),
+        result => {
+            *config = string_config;
+            Ok(result.is_ok())
+        }
+    }
+}
+
+/// Sets the key at `path` below `value`, or removes it if `new` is `None`
+///
+/// Missing tables are created, and an index one past the end of an array appends a
+/// table to it. Removing a key that doesn't exist does nothing.
+fn set_key(
+    value: &mut toml::Value,
+    path: &[String],
+    new: Option<toml::Value>,
+) -> anyhow::Result<()> {
+    let Some((key, rest)) = path.split_first() else {
+        if let Some(new) = new {
+            *value = new;
+        }
+        return Ok(());
+    };
+    if path.iter().any(String::is_empty) {
+        bail!("empty key");
+    }
+    if rest.is_empty() && new.is_none() {
+        if let toml::Value::Table(table) = value {
+            table.remove(key);
+        }
+        return Ok(());
+    }
+
+    let child = match value {
+        toml::Value::Table(table) if new.is_none() && !table.contains_key(key) => return Ok(()),
+        toml::Value::Table(table) => table.entry(key.clone()).or_insert_with(|| {
+            match rest.first().map(|next| next.parse::<usize>()) {
+                Some(Ok(_)) => toml::Value::Array(Vec::new()),
+                _ => toml::Value::Table(toml::Table::new()),
+            }
+        }),
+        toml::Value::Array(array) => {
+            let index: usize = key
+                .parse()
+                .with_context(|| format!("expected an array index, got `{}`", key))?;
+            if index == array.len() && new.is_some() {
+                array.push(toml::Value::Table(toml::Table::new()));
+            }
+            let len = array.len();
+            match array.get_mut(index) {
+                Some(child) => child,
+                None if new.is_none() => return Ok(()),
+                None => bail!("index {} is out of range for an array of {}", index, len),
+            }
+        }
+        _ if new.is_none() => return Ok(()),
+        other => bail!(
+            "can't set `{}` inside a value of type {}",
+            key,
+            other.type_str()
+        ),
+    };
+    set_key(child, rest, new)
+}
+
+/// Compiles the `script_path` or `command` to run on layer change
+fn layer_command(
+    script_path: &Option<String>,
+    command: &Option<Vec<String>>,
+) -> anyhow::Result<Vec<Action>> {
+    let command = match (script_path, command) {
+        (Some(_), Some(_)) => bail!("only one of `script_path` and `command` can be set"),
+        (Some(script_path), None) => Command::Script(script_path.clone()),
+        (None, Some(argv)) => {
+            Command::new(&CommandConfig::Argv(argv.clone())).context("invalid `command`")?
+        }
+        (None, None) => return Ok(Vec::new()),
+    };
+    Ok(vec![Action::command(command)])
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessagePushConfig {
+    /// Script to execute for pushed messages that don't match a route
+    #[serde(default)]
+    pub script_path: Option<String>,
+
+    /// Top-level field of the pushed message used to pick a route
+    #[serde(default)]
+    pub route_field: Option<String>,
+
+    /// Scripts keyed by the value of `route_field`
+    #[serde(default)]
+    pub routes: HashMap<String, String>,
+}
+
+impl MessagePushConfig {
+    /// Returns the script that should handle `message`, if any
+    pub fn script_for(&self, message: &serde_json::Value) -> Option<&str> {
+        let routed = self
+            .route_field
+            .as_ref()
+            .and_then(|field| message.get(field))
+            .map(json_to_string)
+            .and_then(|value| self.routes.get(&value));
+
+        routed.or(self.script_path.as_ref()).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// Seconds to wait after the first failed attempt
+    #[serde(default = "default_reconnect_initial")]
+    pub initial: f64,
+
+    /// Upper bound for the delay in seconds
+    #[serde(default = "default_reconnect_max")]
+    pub max: f64,
+
+    /// Factor the delay grows by after each failed attempt
+    #[serde(default = "default_reconnect_multiplier")]
+    pub multiplier: f64,
+
+    /// Consecutive failed attempts after which to exit, retries forever if unset
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial: default_reconnect_initial(),
+            max: default_reconnect_max(),
+            multiplier: default_reconnect_multiplier(),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    pub fn to_backoff(&self) -> Result<Backoff, InvalidValue> {
+        let invalid = |key: &str, error: anyhow::Error| {
+            InvalidValue::new(
+                vec![Segment::key("reconnect"), Segment::key(key)],
+                error.context("invalid [reconnect] settings"),
+            )
+        };
+        let initial = Duration::try_from_secs_f64(self.initial)
+            .with_context(|| format!("initial = {}", self.initial))
+            .map_err(|e| invalid("initial", e))?;
+        let max = Duration::try_from_secs_f64(self.max)
+            .with_context(|| format!("max = {}", self.max))
+            .map_err(|e| invalid("max", e))?;
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            return Err(invalid(
+                "multiplier",
+                anyhow!("multiplier must be at least 1, got {}", self.multiplier),
+            ));
+        }
+        if self.max_attempts == Some(0) {
+            return Err(invalid(
+                "max_attempts",
+                anyhow!("max_attempts must be at least 1"),
+            ));
+        }
+
+        Ok(Backoff {
+            initial,
+            max,
+            multiplier: self.multiplier,
+            max_attempts: self.max_attempts,
+        })
+    }
+}
+
+fn default_reconnect_initial() -> f64 {
+    1.0
+}
+
+fn default_reconnect_max() -> f64 {
+    30.0
+}
+
+fn default_reconnect_multiplier() -> f64 {
+    2.0
+}
+
+fn default_script_timeout() -> f64 {
+    30.0
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// Returns the commented config file written by `init`, running `script_path` on layer change
+pub fn default_config(script_path: &str) -> String {
+    let port = 5829;
+
+    format!(
+        r#"# Kanata TCP Client Configuration
+# Changes are picked up without a restart, except for [[kanata]] instances and [broadcast]
+# Every key can be overridden with a KANATA_OBSERVER_ environment variable, e.g.
+# KANATA_OBSERVER_PORT or KANATA_OBSERVER_RECONNECT__MAX_ATTEMPTS
+
+# Host that kanata's TCP server is listening on (optional, defaults to 127.0.0.1)
+# Accepts an IPv4 address, an IPv6 address or a hostname, resolved on every
+# connection attempt
+# host = "127.0.0.1"
+
+# Port that kanata's TCP server is listening on
+port = {}
+
+# Path to the script to execute on layer change
+# The layer name will be passed as the first argument, and the previous
+# layer (if known) as the second argument. Every script also gets these
+# environment variables (unknown values are left unset):
+#   KANATA_EVENT        initial, layer_change, enter, exit, config_reload
+#                       or message_push
+#   KANATA_LAYER        the new layer, or the current one for other events
+#   KANATA_DISPLAY_NAME display_name of the [layers] table matching the layer
+#   KANATA_PREV_LAYER   the previous layer
+#   KANATA_LAYER_INDEX  position of the layer in kanata's layer list
+#   KANATA_LAYER_COUNT  number of layers in kanata's layer list
+#   KANATA_INSTANCE     name of the kanata instance
+#   KANATA_HOST         host of the kanata instance
+#   KANATA_PORT         port of the kanata instance
+#   KANATA_TIMESTAMP    Unix time in milliseconds when the event was received
+#   KANATA_CONFIG_PATH  path of the reloaded kanata config, for config_reload
+script_path = {}
+
+# Or call a program directly instead of script_path (optional)
+# Arguments may contain {{layer}}, {{prev}}, {{instance}}, {{display_name}} and
+# {{index}}, and the command gets no implicit arguments
+# command = ["sketchybar", "--set", "kanata", "label={{display_name}}"]
+
+# Path to the script to execute when kanata reloads its config (optional)
+# The path of the reloaded kanata config will be passed as the first argument
+# on_config_reload = "~/.config/kanata-observer/config_reload.sh"
+
+# Log level: "off", "error", "warn", "info", "debug", or "trace"
+log_level = "info"
+
+# Seconds after which a running script is killed, 0 to let scripts run forever
+# Scripts run one at a time, in order, without blocking the connection to kanata
+# script_timeout = 30.0
+
+# Also pass the event as a JSON object on stdin, with the same fields as the
+# environment variables in lowercase without the KANATA_ prefix
+# stdin_json = false
+
+# Only run layer scripts once the layer has been stable for this many
+# milliseconds, e.g. to skip layers passed through while holding a key
+# debounce_ms = 0
+
+# Kill running scripts and skip queued ones when the layer changes, so only
+# the scripts for the latest layer run; changes dropped by debouncing don't count
+# latest_wins = false
+
+# Scripts to execute when kanata pushes a message via `push-msg` (optional)
+# The raw message is passed as JSON on stdin and its top-level fields as
+# KANATA_MSG_<FIELD> environment variables
+# [message_push]
+# script_path = "~/.config/kanata-observer/message_push.sh"
+# route_field = "type"
+#
+# [message_push.routes]
+# notify = "~/.config/kanata-observer/notify.sh"
+
+# Scripts for specific layers (optional)
+# Tables are keyed by layer name, glob (e.g. "nav-*") or regex prefixed with
+# "re:". An exact name wins, otherwise the first matching table in this file
+# is used. Layers without a matching table run `script_path`. Commands can be
+# script paths or program and arguments lists like `command`
+# [layers.gaming]
+# display_name = "Gaming"
+# commands = [
+#     "~/.config/kanata-observer/gaming.sh",
+#     ["tmux", "set", "-g", "status-right", "{{display_name}}"],
+# ]
+#
+# Commands run in order. Entries written as tables can skip the rest of the
+# list when they fail with on_error = "stop", or group commands to start them
+# all at once with parallel = true
+# [layers.nav]
+# commands = [
+#     {{ parallel = true, commands = [
+#         ["sketchybar", "--set", "kanata", "label={{layer}}"],
+#         ["tmux", "set", "-g", "status-right", "{{layer}}"],
+#     ] }},
+#     {{ command = ["pgrep", "dunst"], on_error = "stop" }},
+#     "~/.config/kanata-observer/show_nav_help.sh",
+# ]
+#
+# [layers."nav-*"]
+# commands = [
+#     "~/.config/kanata-observer/nav.sh",
+#     "~/.config/kanata-observer/layer_change.sh",
+# ]
+#
+# write_file entries replace a file with the layer name, or with the event as
+# JSON with format = "json", without starting a process
+# Catch-all tables go last, since the first matching table is used
+# [layers."*"]
+# commands = [
+#     {{ write_file = "~/.cache/kanata/{{instance}}.layer" }},
+#     {{ write_file = "~/.cache/kanata/{{instance}}.json", format = "json" }},
+# ]
+
+# Scripts to run when entering and leaving layers (optional)
+# A rule is entered when the layer changes from a layer matching `from` to a
+# layer matching `to` (either may be omitted to match any layer), running
+# `on_enter`. Once the layer changes to one that no longer matches `to`,
+# `on_exit` runs, so it can undo what `on_enter` did
+# [[rules]]
+# to = "gaming"
+# on_enter = ["~/.config/kanata-observer/dnd_on.sh"]
+# on_exit = ["~/.config/kanata-observer/dnd_off.sh"]
+
+# Exponential backoff between attempts to reconnect to kanata (optional)
+# After a failed attempt the observer waits `initial` seconds, growing by
+# `multiplier` up to `max` seconds. If kanata closes the connection cleanly
+# the observer reconnects immediately.
+# [reconnect]
+# initial = 1.0
+# max = 30.0
+# multiplier = 2.0
+# Exit with code 3 after this many consecutive failed attempts
+# max_attempts = 10
+
+# Re-broadcast kanata's messages to local tools (optional)
+# Every message is forwarded as a line of JSON in kanata's format. New
+# subscribers first get the layer names and current layer
+# [broadcast]
+# socket = "~/.cache/kanata-observer/broadcast.sock"
+# port = 1013
+
+# Observe several kanata instances, e.g. one per keyboard (optional)
+# Each instance gets its own connection; unset values fall back to the
+# top-level ones. The instance name is passed to scripts in KANATA_INSTANCE
+# [[kanata]]
+# name = "laptop"
+# port = 1012
+#
+# [[kanata]]
+# name = "external"
+# host = "127.0.0.1"
+# port = 1013
+# script_path = "~/.config/kanata-observer/external_layer_change.sh"
+# on_config_reload = "~/.config/kanata-observer/external_config_reload.sh"
+#
+# [kanata.broadcast]
+# socket = "~/.cache/kanata-observer/external.sock"
+"#,
+        port,
+        // Serialized as a TOML string, as the path may contain `"` or `\`
+        toml::Value::String(script_path.to_string())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn did_you_mean_suggests_the_closest_name() {
+        let message = "unknown field `log_levl`, expected one of `log_level`, `port`";
+        assert_eq!(
+            did_you_mean(message).as_deref(),
+            Some("did you mean `log_level`?")
+        );
+        let message = "unknown variant `debgu`, expected one of `info`, `debug`";
+        assert_eq!(
+            did_you_mean(message).as_deref(),
+            Some("did you mean `debug`?")
+        );
+        let message = "unknown field `prot`, expected `port`";
+        assert_eq!(
+            did_you_mean(message).as_deref(),
+            Some("did you mean `port`?")
+        );
+    }
+
+    #[test]
+    fn did_you_mean_ignores_distant_names() {
+        let message = "unknown field `xyz`, expected one of `log_level`, `port`";
+        assert_eq!(did_you_mean(message), None);
+    }
+
+    #[test]
+    fn did_you_mean_ignores_other_messages() {
+        assert_eq!(
+            did_you_mean("unknown field `port`, there are no fields"),
+            None
+        );
+        assert_eq!(
+            did_you_mean("invalid type: string \"x\", expected u16"),
+            None
+        );
+        assert_eq!(did_you_mean(""), None);
+    }
+
+    fn table(contents: &str) -> toml::Value {
+        toml::Value::Table(toml::from_str(contents).unwrap())
+    }
+
+    fn path(key: &str) -> Vec<String> {
+        key.split('.').map(str::to_string).collect()
+    }
+
+    fn overrides(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (format!("{}{}", ENV_PREFIX, name), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_value_parses_toml_and_falls_back_to_a_string() {
+        assert_eq!(env_value("1012"), Some(toml::Value::Integer(1012)));
+        assert_eq!(env_value("true"), Some(toml::Value::Boolean(true)));
+        assert_eq!(
+            env_value(r#"["a", "b"]"#),
+            Some(toml::Value::Array(vec!["a".into(), "b".into()]))
+        );
+        assert_eq!(env_value("\"1\""), Some("1".into()));
+        assert_eq!(env_value("~/layer.sh"), Some("~/layer.sh".into()));
+        assert_eq!(env_value("[unclosed"), Some("[unclosed".into()));
+        assert_eq!(env_value(""), None);
+    }
+
+    #[test]
+    fn set_key_creates_missing_tables() {
+        let mut value = table("port = 1");
+        set_key(&mut value, &path("reconnect.max"), Some(5.into())).unwrap();
+        assert_eq!(value, table("port = 1\n[reconnect]\nmax = 5"));
+    }
+
+    #[test]
+    fn set_key_appends_one_past_the_end_of_an_array() {
+        let mut value = table("[[kanata]]\nport = 1");
+        set_key(&mut value, &path("kanata.1.port"), Some(2.into())).unwrap();
+        set_key(&mut value, &path("kanata.0.port"), Some(3.into())).unwrap();
+        assert_eq!(value, table("[[kanata]]\nport = 3\n[[kanata]]\nport = 2"));
+
+        let mut value = table("");
+        set_key(&mut value, &path("kanata.0.port"), Some(2.into())).unwrap();
+        assert_eq!(value, table("[[kanata]]\nport = 2"));
+    }
+
+    #[test]
+    fn set_key_rejects_invalid_paths() {
+        let mut value = table("port = 1\n[[kanata]]\nport = 1");
+        let error = |value: &mut toml::Value, key: &str| {
+            format!(
+                "{}",
+                set_key(value, &path(key), Some(2.into())).unwrap_err()
+            )
+        };
+        assert_eq!(
+            error(&mut value, "kanata.2.port"),
+            "index 2 is out of range for an array of 1"
+        );
+        assert_eq!(
+            error(&mut value, "kanata.x"),
+            "expected an array index, got `x`"
+        );
+        assert_eq!(
+            error(&mut value, "port.x"),
+            "can't set `x` inside a value of type integer"
+        );
+        assert_eq!(error(&mut value, "reconnect..max"), "empty key");
+        assert_eq!(value, table("port = 1\n[[kanata]]\nport = 1"));
+    }
+
+    #[test]
+    fn set_key_removes_keys() {
+        let mut value = table("port = 1\n[reconnect]\nmax = 5");
+        set_key(&mut value, &path("reconnect.max"), None).unwrap();
+        set_key(&mut value, &path("port"), None).unwrap();
+        assert_eq!(value, table("[reconnect]"));
+    }
+
+    #[test]
+    fn set_key_removing_missing_keys_changes_nothing() {
+        let mut value = table("port = 1\n[[kanata]]\nport = 1");
+        for key in [
+            "reconnect.max",
+            "kanata.1.port",
+            "kanata.5",
+            "port.x",
+            "host",
+        ] {
+            set_key(&mut value, &path(key), None).unwrap();
+        }
+        assert_eq!(value, table("port = 1\n[[kanata]]\nport = 1"));
+    }
+
+    #[test]
+    fn parse_applies_overrides() {
+        let config = Config::parse(
+            "port = 1\nscript_path = \"a.sh\"",
+            &overrides(&[("PORT", "2"), ("RECONNECT__MAX", "5"), ("SCRIPT_PATH", "")]),
+        )
+        .unwrap();
+        assert_eq!(config.port, Some(2));
+        assert_eq!(config.reconnect.max, 5.0);
+        assert_eq!(config.script_path, None);
+    }
+
+    #[test]
+    fn parse_falls_back_to_strings_for_string_keys() {
+        let config = Config::parse(
+            "port = 1",
+            &overrides(&[("HOST", "1.5"), ("SCRIPT_PATH", "true")]),
+        )
+        .unwrap();
+        assert_eq!(config.host, "1.5");
+        assert_eq!(config.script_path.as_deref(), Some("true"));
+
+        // Every entry of a new [[kanata]] instance is set before it's complete
+        let config = Config::parse(
+            "",
+            &overrides(&[("KANATA__0__NAME", "1"), ("KANATA__0__PORT", "2")]),
+        )
+        .unwrap();
+        assert_eq!(config.kanata[0].name, "1");
+        assert_eq!(config.kanata[0].port, Some(2));
+    }
+
+    #[test]
+    fn parse_keeps_the_position_of_errors_in_the_file() {
+        let error = Config::parse(
+            "port = 1\nlog_levl = \"info\"",
+            &overrides(&[("PORT", "2")]),
+        )
+        .unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("line 2, column 1"), "{}", message);
+        assert!(message.contains("did you mean `log_level`?"), "{}", message);
+    }
+
+    #[test]
+    fn parse_names_the_override_that_made_the_config_invalid() {
+        let error = |pairs| {
+            format!(
+                "{:#}",
+                Config::parse("port = 1", &overrides(pairs)).unwrap_err()
+            )
+        };
+        let message = error(&[("HOST", "a"), ("PORT", "x"), ("SCRIPT_PATH", "a.sh")]);
+        assert!(
+            message.starts_with("invalid KANATA_OBSERVER_PORT: invalid type"),
+            "{}",
+            message
+        );
+        let message = error(&[("LOG_LEVL", "debug")]);
+        assert!(
+            message.starts_with("invalid KANATA_OBSERVER_LOG_LEVL: unknown field `log_levl`"),
+            "{}",
+            message
+        );
+        assert!(
+            message.ends_with("did you mean `log_level`?"),
+            "{}",
+            message
+        );
+        let message = error(&[("PORT__X", "1")]);
+        assert_eq!(
+            message,
+            "invalid KANATA_OBSERVER_PORT__X: can't set `x` inside a value of type integer"
+        );
+    }
+
+    #[test]
+    fn on_exit_needs_to() {
+        let config: Config = toml::from_str(
+            "port = 1\n[[rules]]\nto = \"gaming\"\non_exit = [\"a\"]\n\
+             [[rules]]\nfrom = \"gaming\"\non_exit = [\"b\"]",
+        )
+        .unwrap();
+        let invalid = config.validate_options().unwrap_err();
+        assert_eq!(
+            invalid.path,
+            [
+                Segment::key("rules"),
+                Segment::Index(1),
+                Segment::key("on_exit")
+            ]
+        );
+    }
+
+    #[test]
+    fn default_config_is_valid_for_any_script_path() {
+        for path in [
+            "/tmp/layer_change.sh",
+            "/tmp/q\"d/layer_change.sh",
+            "C:\\x\\layer.sh",
+        ] {
+            let config: Config = toml::from_str(&default_config(path)).unwrap();
+            assert_eq!(config.script_path.as_deref(), Some(path));
+            config.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn env_path_keeps_the_case_of_names() {
+        assert_eq!(env_path("RECONNECT__MAX"), ["reconnect", "max"]);
+        assert_eq!(env_path("KANATA__0__PORT"), ["kanata", "0", "port"]);
+        assert_eq!(
+            env_path("LAYERS__Gaming__DISPLAY_NAME"),
+            ["layers", "Gaming", "display_name"]
+        );
+        assert_eq!(
+            env_path("MESSAGE_PUSH__ROUTES__Notify"),
+            ["message_push", "routes", "Notify"]
+        );
+        assert_eq!(
+            env_path("MESSAGE_PUSH__ROUTE_FIELD"),
+            ["message_push", "route_field"]
+        );
+    }
+
+    #[test]
+    fn parse_overrides_case_sensitive_layer_names() {
+        let config = Config::parse(
+            "port = 1\n[layers.Gaming]\ndisplay_name = \"G\"",
+            &overrides(&[("LAYERS__Gaming__DISPLAY_NAME", "Game")]),
+        )
+        .unwrap();
+        assert_eq!(config.layers.len(), 1);
+        assert_eq!(
+            config.layers["Gaming"].display_name.as_deref(),
+            Some("Game")
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Details about the event a script runs for, passed as `KANATA_*` environment
+/// variables and, with `stdin_json`, as JSON on stdin
+#[derive(Debug, Serialize)]
+pub struct EventContext {
+    /// `initial`, `layer_change`, `enter`, `exit`, `config_reload` or `message_push`
+    pub event: &'static str,
+    pub instance: String,
+    pub host: String,
+    pub port: u16,
+    /// Unix time in milliseconds when the observer received the event
+    pub timestamp: u64,
+    /// The new layer for layer events, the current layer otherwise
+    pub layer: Option<String>,
+    /// `display_name` of the `[layers]` table matching `layer`
+    pub display_name: Option<String>,
+    pub prev_layer: Option<String>,
+    /// Position of `layer` in kanata's layer list
+    pub layer_index: Option<usize>,
+    pub layer_count: Option<usize>,
+    /// Path of the reloaded kanata config for `config_reload`
+    pub config_path: Option<String>,
+}
+
+impl EventContext {
+    pub fn envs(&self) -> Vec<(String, String)> {
+        let envs = [
+            ("KANATA_EVENT", Some(self.event.to_string())),
+            ("KANATA_INSTANCE", Some(self.instance.clone())),
+            ("KANATA_HOST", Some(self.host.clone())),
+            ("KANATA_PORT", Some(self.port.to_string())),
+            ("KANATA_TIMESTAMP", Some(self.timestamp.to_string())),
+            ("KANATA_LAYER", self.layer.clone()),
+            ("KANATA_DISPLAY_NAME", self.display_name.clone()),
+            ("KANATA_PREV_LAYER", self.prev_layer.clone()),
+            (
+                "KANATA_LAYER_INDEX",
+                self.layer_index.map(|i| i.to_string()),
+            ),
+            (
+                "KANATA_LAYER_COUNT",
+                self.layer_count.map(|n| n.to_string()),
+            ),
+            ("KANATA_CONFIG_PATH", self.config_path.clone()),
+        ];
+        envs.into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("event context serializes to JSON")
+    }
+}
+
+/// Current Unix time in milliseconds
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX))
+        .unwrap_or_default()
+}
@@ -0,0 +1,108 @@
+use crate::config::default_config;
+use anyhow::{bail, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the starter script, written next to the config file
+const SCRIPT_NAME: &str = "layer_change.sh";
+
+const SCRIPT: &str = r#"#!/bin/sh
+# Executed by kanata-layer-observer on every layer change
+#
+#   $1  the new layer
+#   $2  the previous layer, empty if unknown
+#
+# KANATA_EVENT, KANATA_INSTANCE and the other KANATA_* environment variables
+# describe the event in more detail
+
+layer="$1"
+previous="$2"
+
+case "$layer" in
+    *)
+        echo "switched from ${previous:-unknown} to $layer"
+        ;;
+esac
+"#;
+
+/// Writes the default config file to `config` and the starter script next to it,
+/// refusing to replace existing files unless `force` is set
+///
+/// `config` is the path as given on the command line, with `~` unexpanded so the
+/// config file refers to the script the same way.
+pub fn init(config: &str, force: bool) -> anyhow::Result<()> {
+    let script = script_path(config)?;
+    let paths = [expand(config), expand(&script)];
+    if !force {
+        let existing: Vec<_> = paths
+            .iter()
+            .filter(|path| path.exists())
+            .map(|path| path.display().to_string())
+            .collect();
+        if !existing.is_empty() {
+            bail!(
+                "refusing to overwrite existing {}, pass --force to replace it",
+                existing.join(" and ")
+            );
+        }
+    }
+
+    write(&paths[0], &default_config(&script), false)?;
+    write(&paths[1], SCRIPT, true)?;
+    eprintln!("Created config file {}", paths[0].display());
+    eprintln!("Created script {}", paths[1].display());
+    eprintln!("Please edit them with your desired settings.");
+    Ok(())
+}
+
+/// Writes the default config file to `config` for `--create-default`, and the starter
+/// script unless it already exists
+pub fn create_default(config: &str) -> anyhow::Result<()> {
+    let script = script_path(config)?;
+    let config_path = expand(config);
+    write(&config_path, &default_config(&script), false)?;
+    eprintln!("Created default config file {}", config_path.display());
+
+    let script_path = expand(&script);
+    if !script_path.exists() {
+        write(&script_path, SCRIPT, true)?;
+        eprintln!("Created script {}", script_path.display());
+    }
+    eprintln!("Please edit it with your desired settings.");
+    Ok(())
+}
+
+/// Returns the path of the starter script next to `config`, made absolute since
+/// scripts don't run in the directory of the config file
+fn script_path(config: &str) -> anyhow::Result<String> {
+    let script = Path::new(config).with_file_name(SCRIPT_NAME);
+    if script.is_absolute() || config.starts_with('~') {
+        return Ok(script.display().to_string());
+    }
+    let script = std::path::absolute(&script)
+        .with_context(|| format!("failed to resolve {}", script.display()))?;
+    Ok(script.display().to_string())
+}
+
+fn expand(path: &str) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(path).as_ref())
+}
+
+fn write(path: &Path, contents: &str, executable: bool) -> anyhow::Result<()> {
+    // Create parent directory if it doesn't exist
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    if executable {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to make {} executable", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = executable;
+    Ok(())
+}
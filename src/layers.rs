@@ -0,0 +1,312 @@
+use crate::command::Action;
+use crate::config::{LayerConfig, RuleConfig};
+use anyhow::Context;
+use indexmap::IndexMap;
+use regex::Regex;
+
+/// Matches layer names: exactly, by glob (`nav-*`), or by regex (`re:^nav-(left|right)$`)
+#[derive(Debug, Clone)]
+pub enum LayerPattern {
+    Exact(String),
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl LayerPattern {
+    pub fn parse(pattern: &str) -> anyhow::Result<Self> {
+        if let Some(regex) = pattern.strip_prefix("re:") {
+            let regex = Regex::new(regex).with_context(|| format!("invalid regex {:?}", regex))?;
+            return Ok(LayerPattern::Regex(regex));
+        }
+        if pattern.contains(['*', '?', '[']) {
+            let glob = glob::Pattern::new(pattern)
+                .with_context(|| format!("invalid glob {:?}", pattern))?;
+            return Ok(LayerPattern::Glob(glob));
+        }
+        Ok(LayerPattern::Exact(pattern.to_string()))
+    }
+
+    pub fn matches(&self, layer: &str) -> bool {
+        match self {
+            LayerPattern::Exact(name) => name == layer,
+            LayerPattern::Glob(glob) => glob.matches(layer),
+            LayerPattern::Regex(regex) => regex.is_match(layer),
+        }
+    }
+}
+
+/// A `[[rules]]` entry with its patterns compiled
+///
+/// A rule is entered when the layer changes from a layer matching `from` to a layer
+/// matching `to`, running `on_enter`. It stays active while the layer keeps matching
+/// `to`, and runs `on_exit` once the layer changes to one that doesn't. A rule without
+/// `to` is never active, so it runs `on_enter` on every change from `from`.
+#[derive(Debug)]
+pub struct Rule {
+    from: Option<LayerPattern>,
+    to: Option<LayerPattern>,
+    pub on_enter: Vec<Action>,
+    pub on_exit: Vec<Action>,
+}
+
+impl Rule {
+    fn new(rule: &RuleConfig) -> anyhow::Result<Self> {
+        let parse =
+            |pattern: &Option<String>| pattern.as_deref().map(LayerPattern::parse).transpose();
+        Ok(Rule {
+            from: parse(&rule.from).context("invalid `from`")?,
+            to: parse(&rule.to).context("invalid `to`")?,
+            on_enter: Action::all(&rule.on_enter).context("invalid `on_enter`")?,
+            on_exit: Action::all(&rule.on_exit).context("invalid `on_exit`")?,
+        })
+    }
+
+    /// Whether changing from `prev` to `layer` enters the rule; an unknown previous
+    /// layer only matches rules without `from`, and staying on the same layer, e.g.
+    /// after reconnecting, enters nothing
+    pub fn enters(&self, prev: Option<&str>, layer: &str) -> bool {
+        let from = match (&self.from, prev) {
+            (_, Some(prev)) if prev == layer => false,
+            (None, _) => true,
+            (Some(pattern), Some(prev)) => pattern.matches(prev),
+            (Some(_), None) => false,
+        };
+        from && self.to.as_ref().is_none_or(|to| to.matches(layer))
+    }
+
+    /// Whether an entered rule stays active on `layer`, until `on_exit` runs
+    pub fn stays_in(&self, layer: &str) -> bool {
+        self.to.as_ref().is_some_and(|to| to.matches(layer))
+    }
+}
+
+/// A `[layers.<pattern>]` table with its commands compiled
+#[derive(Debug)]
+pub struct LayerHandler {
+    pub commands: Vec<Action>,
+    pub display_name: Option<String>,
+}
+
+impl LayerHandler {
+    fn new(layer: &LayerConfig) -> anyhow::Result<Self> {
+        Ok(LayerHandler {
+            commands: Action::all(&layer.commands).context("invalid `commands`")?,
+            display_name: layer.display_name.clone(),
+        })
+    }
+}
+
+/// The `[layers.<pattern>]` tables and `[[rules]]` with their patterns compiled
+#[derive(Debug, Default)]
+pub struct LayerHandlers {
+    handlers: Vec<(LayerPattern, LayerHandler)>,
+    pub rules: Vec<Rule>,
+}
+
+impl LayerHandlers {
+    pub fn new(
+        layers: &IndexMap<String, LayerConfig>,
+        rules: &[RuleConfig],
+    ) -> anyhow::Result<Self> {
+        let handlers = layers
+            .iter()
+            .map(|(pattern, layer)| {
+                let handler = LayerHandler::new(layer)
+                    .with_context(|| format!("invalid [layers.{:?}] table", pattern))?;
+                let pattern = LayerPattern::parse(pattern)
+                    .with_context(|| format!("invalid [layers] pattern {:?}", pattern))?;
+                Ok((pattern, handler))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let rules = rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                Rule::new(rule).with_context(|| format!("invalid [[rules]] entry {}", i + 1))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(LayerHandlers { handlers, rules })
+    }
+
+    /// Returns the table for `layer`: an exact match wins, then the first matching
+    /// pattern in config file order
+    pub fn get(&self, layer: &str) -> Option<&LayerHandler> {
+        let exact = self
+            .handlers
+            .iter()
+            .find(|(pattern, _)| matches!(pattern, LayerPattern::Exact(name) if name == layer));
+        exact
+            .or_else(|| {
+                self.handlers
+                    .iter()
+                    .find(|(pattern, _)| pattern.matches(layer))
+            })
+            .map(|(_, handler)| handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(from: Option<&str>, to: Option<&str>) -> Rule {
+        Rule::new(&RuleConfig {
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+            on_enter: Vec::new(),
+            on_exit: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn rule_enters_on_matching_from_and_to() {
+        let rule = rule(Some("base"), Some("nav-*"));
+        assert!(rule.enters(Some("base"), "nav-left"));
+        assert!(!rule.enters(Some("gaming"), "nav-left"));
+        assert!(!rule.enters(Some("base"), "gaming"));
+        assert!(!rule.enters(None, "nav-left"));
+        assert!(rule.stays_in("nav-right"));
+        assert!(!rule.stays_in("base"));
+    }
+
+    #[test]
+    fn rule_without_from_enters_from_any_layer() {
+        let rule = rule(None, Some("gaming"));
+        assert!(rule.enters(None, "gaming"));
+        assert!(rule.enters(Some("base"), "gaming"));
+        assert!(!rule.enters(Some("base"), "nav"));
+    }
+
+    #[test]
+    fn rule_without_to_enters_on_leaving_from_but_never_stays() {
+        let rule = rule(Some("gaming"), None);
+        assert!(rule.enters(Some("gaming"), "base"));
+        assert!(rule.enters(Some("gaming"), "nav"));
+        assert!(!rule.enters(Some("base"), "gaming"));
+        assert!(!rule.enters(None, "base"));
+        assert!(!rule.stays_in("base"));
+        assert!(!rule.stays_in("gaming"));
+    }
+
+    #[test]
+    fn rule_is_not_entered_by_the_same_layer() {
+        assert!(!rule(Some("gaming"), None).enters(Some("gaming"), "gaming"));
+        assert!(!rule(None, None).enters(Some("base"), "base"));
+        assert!(!rule(Some("re:.*"), Some("re:.*")).enters(Some("base"), "base"));
+        assert!(rule(None, None).enters(None, "base"));
+    }
+
+    fn matches(pattern: &str, layer: &str) -> bool {
+        LayerPattern::parse(pattern).unwrap().matches(layer)
+    }
+
+    /// Handlers for `patterns` in order, each named after its pattern
+    fn handlers(patterns: &[&str]) -> LayerHandlers {
+        let layers = patterns
+            .iter()
+            .map(|pattern| {
+                let layer = LayerConfig {
+                    commands: Vec::new(),
+                    display_name: Some(pattern.to_string()),
+                };
+                (pattern.to_string(), layer)
+            })
+            .collect();
+        LayerHandlers::new(&layers, &[]).unwrap()
+    }
+
+    fn get<'a>(handlers: &'a LayerHandlers, layer: &str) -> Option<&'a str> {
+        handlers
+            .get(layer)
+            .and_then(|handler| handler.display_name.as_deref())
+    }
+
+    #[test]
+    fn pattern_kinds() {
+        assert!(matches!(
+            LayerPattern::parse("nav").unwrap(),
+            LayerPattern::Exact(_)
+        ));
+        assert!(matches!(
+            LayerPattern::parse("nav-*").unwrap(),
+            LayerPattern::Glob(_)
+        ));
+        assert!(matches!(
+            LayerPattern::parse("nav?").unwrap(),
+            LayerPattern::Glob(_)
+        ));
+        assert!(matches!(
+            LayerPattern::parse("[ab]").unwrap(),
+            LayerPattern::Glob(_)
+        ));
+        assert!(matches!(
+            LayerPattern::parse("re:nav").unwrap(),
+            LayerPattern::Regex(_)
+        ));
+        // Only a leading `re:` makes a regex
+        assert!(matches!(
+            LayerPattern::parse("pre:nav").unwrap(),
+            LayerPattern::Exact(_)
+        ));
+    }
+
+    #[test]
+    fn exact_patterns_match_the_whole_name() {
+        assert!(matches("nav", "nav"));
+        assert!(!matches("nav", "nav-left"));
+        assert!(!matches("nav", "Nav"));
+        assert!(matches("re.nav", "re.nav"));
+        assert!(!matches("re.nav", "rexnav"));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(matches("nav-*", "nav-left"));
+        assert!(matches("nav-*", "nav-"));
+        assert!(!matches("nav-*", "nav"));
+        assert!(!matches("nav-*", "xnav-left"));
+        assert!(matches("nav?", "nav1"));
+        assert!(!matches("nav?", "nav12"));
+        assert!(matches("layer[12]", "layer2"));
+        assert!(!matches("layer[12]", "layer3"));
+    }
+
+    #[test]
+    fn regex_patterns_are_unanchored_unless_anchored() {
+        assert!(matches("re:^nav-(left|right)$", "nav-left"));
+        assert!(!matches("re:^nav-(left|right)$", "nav-up"));
+        assert!(matches("re:nav", "my-nav-layer"));
+        assert!(matches("re:", "anything"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let error = |pattern| format!("{:#}", LayerPattern::parse(pattern).unwrap_err());
+        assert!(error("re:(").starts_with("invalid regex \"(\""));
+        assert!(error("nav-[").starts_with("invalid glob \"nav-[\""));
+    }
+
+    #[test]
+    fn get_prefers_exact_matches() {
+        let handlers = handlers(&["nav-*", "re:nav", "nav-left"]);
+        assert_eq!(get(&handlers, "nav-left"), Some("nav-left"));
+    }
+
+    #[test]
+    fn get_falls_back_to_the_first_matching_pattern() {
+        let first = handlers(&["re:^nav", "nav-*", "base"]);
+        assert_eq!(get(&first, "nav-right"), Some("re:^nav"));
+        assert_eq!(get(&first, "base"), Some("base"));
+        assert_eq!(get(&first, "gaming"), None);
+
+        let second = handlers(&["nav-*", "re:^nav"]);
+        assert_eq!(get(&second, "nav-right"), Some("nav-*"));
+        assert_eq!(get(&second, "navigation"), Some("re:^nav"));
+    }
+
+    #[test]
+    fn get_without_handlers() {
+        assert_eq!(get(&LayerHandlers::default(), "base"), None);
+    }
+}
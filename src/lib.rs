@@ -0,0 +1,19 @@
+//! Client for kanata's TCP server
+//!
+//! [`KanataClient`] connects to kanata, sends [`ClientMessage`]s and yields typed
+//! [`Event`]s, reconnecting whenever the connection is lost. With the `async` feature,
+//! [`async_client::KanataClient`] opens a single tokio connection that can send from
+//! several tasks at once, with a stream of the [`ServerMessage`]s kanata sends. It
+//! doesn't reconnect: the stream ends when the connection is closed or lost.
+
+mod addr;
+#[cfg(feature = "async")]
+pub mod async_client;
+mod backoff;
+mod client;
+mod protocol;
+
+pub use addr::KanataAddr;
+pub use backoff::Backoff;
+pub use client::{DisconnectHandle, Event, KanataClient};
+pub use protocol::{ClientMessage, FakeKeyActionMessage, ServerMessage, ServerResponse};
@@ -1,99 +1,155 @@
-use clap::Parser;
-use serde::{Deserialize, Serialize};
+mod broadcast;
+mod check;
+mod command;
+mod config;
+mod event;
+mod init;
+mod layers;
+mod observer;
+mod reload;
+mod script;
+mod watch;
+
+use anyhow::Context;
+use broadcast::Broadcaster;
+use clap::{Parser, Subcommand, ValueEnum};
+use config::{Config, Instance};
+use kanata_layer_observer::{
+    ClientMessage, FakeKeyActionMessage, KanataAddr, KanataClient, ServerMessage, ServerResponse,
+};
+use reload::{Settings, SharedSettings};
 use std::fs;
-use std::io::{BufRead, BufReader};
-use std::net::{SocketAddr, TcpStream};
-use std::process::{exit, Command};
+use std::process::exit;
 use std::time::Duration;
+use watch::WatchOptions;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ServerMessage {
-    LayerChange { new: String },
-    LayerNames { names: Vec<String> },
-    CurrentLayerInfo { name: String, cfg_text: String },
-    ConfigFileReload { new: String },
-    CurrentLayerName { name: String },
-    MessagePush { message: serde_json::Value },
-    Error { msg: String },
-}
+/// Exit code when `reconnect.max_attempts` consecutive connection attempts failed
+pub const EXIT_RECONNECT_GAVE_UP: i32 = 3;
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "status")]
-pub enum ServerResponse {
-    Ok,
-    Error { msg: String },
-}
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Path to configuration file
+    #[clap(
+        short,
+        long,
+        global = true,
+        default_value = "~/.config/kanata-observer/config.toml"
+    )]
+    config: String,
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    /// Port that kanata's TCP server is listening on
-    port: u16,
+    /// Only use the [[kanata]] instance with this name
+    #[clap(short, long, global = true)]
+    instance: Option<String>,
 
-    /// Path to the script to execute on layer change
-    script_path: String,
+    /// Host that kanata's TCP server is listening on (overrides config file)
+    #[clap(long, global = true)]
+    host: Option<String>,
 
-    /// Log level: "info", "debug", or "trace"
-    #[serde(default = "default_log_level")]
-    log_level: String,
-}
+    /// Port that kanata's TCP server is listening on (overrides config file)
+    #[clap(short, long, global = true)]
+    port: Option<u16>,
 
-fn default_log_level() -> String {
-    "info".to_string()
-}
+    /// Enable debug logging (overrides config file)
+    #[clap(short, long, global = true)]
+    debug: bool,
 
-fn create_default_config(path: &str) -> std::io::Result<Config> {
-    let default_config = Config {
-        port: 5829,
-        script_path: "~/.config/kanata-observer/layer_change.sh".to_string(),
-        log_level: "info".to_string(),
-    };
+    /// Enable trace logging (overrides config file)
+    #[clap(short, long, global = true)]
+    trace: bool,
 
-    let toml_content = format!(
-        r#"# Kanata TCP Client Configuration
+    /// Create a default config file if it doesn't exist, instead of exiting with an error
+    #[clap(long, global = true)]
+    create_default: bool,
 
-# Port that kanata's TCP server is listening on
-port = {}
+    #[clap(subcommand)]
+    command: Option<Commands>,
+}
 
-# Path to the script to execute on layer change
-# The layer name will be passed as the first argument
-script_path = "{}"
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Check the config file and the scripts it refers to, without connecting to kanata
+    Check,
 
-# Log level: "info", "debug", or "trace"
-log_level = "{}"
-"#,
-        default_config.port, default_config.script_path, default_config.log_level
-    );
+    /// Write a commented config file and a starter layer_change.sh next to it
+    Init {
+        /// Overwrite existing files
+        #[clap(long)]
+        force: bool,
+    },
 
-    // Create parent directory if it doesn't exist
-    if let Some(parent) = std::path::Path::new(path).parent() {
-        fs::create_dir_all(parent)?;
-    }
+    /// Send a command to kanata and wait for its response
+    Send {
+        #[clap(subcommand)]
+        message: SendCommand,
+    },
 
-    fs::write(path, toml_content)?;
-    eprintln!("Created default config file at: {}", path);
-    eprintln!("Please edit it with your desired settings.");
+    /// Print layer changes to stdout, one layer name per line
+    Watch {
+        /// Print every message from kanata as a line of JSON
+        #[clap(long)]
+        json: bool,
 
-    Ok(default_config)
+        /// Print the current layer and exit
+        #[clap(long)]
+        once: bool,
+
+        /// Read from a running observer's [broadcast] socket or port instead of kanata
+        #[clap(long)]
+        attach: bool,
+    },
 }
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-    /// Path to configuration file
-    #[clap(short, long, default_value = "~/.config/kanata-observer/config.toml")]
-    config: String,
+#[derive(Subcommand, Debug)]
+enum SendCommand {
+    /// Switch kanata to the given layer
+    ChangeLayer { name: String },
 
-    /// Port that kanata's TCP server is listening on (overrides config file)
-    #[clap(short, long)]
-    port: Option<u16>,
+    /// Reload kanata's config file
+    Reload,
 
-    /// Enable debug logging (overrides config file)
-    #[clap(short, long)]
-    debug: bool,
+    /// Act on a virtual/fake key defined in kanata's config
+    FakeKey {
+        name: String,
+        #[clap(value_enum)]
+        action: FakeKeyAction,
+    },
 
-    /// Enable trace logging (overrides config file)
-    #[clap(short, long)]
-    trace: bool,
+    /// Print kanata's layer names, one per line
+    RequestLayerNames,
+}
+
+impl SendCommand {
+    fn to_message(&self) -> ClientMessage {
+        match self {
+            SendCommand::ChangeLayer { name } => ClientMessage::ChangeLayer { new: name.clone() },
+            SendCommand::Reload => ClientMessage::Reload {},
+            SendCommand::FakeKey { name, action } => ClientMessage::ActOnFakeKey {
+                name: name.clone(),
+                action: action.to_message(),
+            },
+            SendCommand::RequestLayerNames => ClientMessage::RequestLayerNames {},
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum FakeKeyAction {
+    Press,
+    Release,
+    Tap,
+    Toggle,
+}
+
+impl FakeKeyAction {
+    fn to_message(self) -> FakeKeyActionMessage {
+        match self {
+            FakeKeyAction::Press => FakeKeyActionMessage::Press,
+            FakeKeyAction::Release => FakeKeyActionMessage::Release,
+            FakeKeyAction::Tap => FakeKeyActionMessage::Tap,
+            FakeKeyAction::Toggle => FakeKeyActionMessage::Toggle,
+        }
+    }
 }
 
 fn main() {
@@ -102,117 +158,262 @@ fn main() {
     // Expand ~ in config path
     let config_path = shellexpand::tilde(&args.config).to_string();
 
-    // Read and parse config file, create default if not found
-    let config: Config = match fs::read_to_string(&config_path) {
-        Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
-            eprintln!("Failed to parse config file {}: {}", config_path, e);
-            exit(1);
-        }),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            // Config file doesn't exist, create a default one
-            match create_default_config(&config_path) {
-                Ok(config) => config,
-                Err(e) => {
-                    eprintln!(
-                        "Failed to create default config file {}: {}",
-                        config_path, e
-                    );
-                    exit(1);
-                }
+    match args.command {
+        Some(Commands::Check) => exit(check::check(&config_path)),
+        Some(Commands::Init { force }) => {
+            if let Err(e) = init::init(&args.config, force) {
+                eprintln!("{:#}", e);
+                exit(1);
             }
+            exit(0);
         }
-        Err(e) => {
-            eprintln!("Failed to read config file {}: {}", config_path, e);
+        _ => {}
+    }
+
+    // A typo in --config shouldn't leave a stray config file behind
+    let missing =
+        fs::metadata(&config_path).is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound);
+    if missing && !args.create_default {
+        eprintln!(
+            "Config file {} doesn't exist, create it with the `init` subcommand or pass --create-default",
+            config_path
+        );
+        exit(1);
+    }
+    if missing {
+        if let Err(e) = init::create_default(&args.config) {
+            eprintln!("Failed to create default config file: {:#}", e);
             exit(1);
         }
-    };
+    }
 
-    // Determine log level (CLI overrides config)
-    let log_level = if args.trace {
-        simplelog::LevelFilter::Trace
-    } else if args.debug {
-        simplelog::LevelFilter::Debug
-    } else {
-        match config.log_level.to_lowercase().as_str() {
-            "trace" => simplelog::LevelFilter::Trace,
-            "debug" => simplelog::LevelFilter::Debug,
-            "info" => simplelog::LevelFilter::Info,
-            _ => simplelog::LevelFilter::Info,
-        }
-    };
+    let config = read_config(&config_path).unwrap_or_else(|e| {
+        eprintln!("{:#}", e);
+        exit(1);
+    });
+
+    let settings = load_settings(&config_path, config, &args).unwrap_or_else(|e| {
+        eprintln!("{:#}", e);
+        exit(1);
+    });
 
+    // Prefix log lines with the instance name when observing several instances
+    let mut log_config = simplelog::ConfigBuilder::new();
+    if settings.instances.len() > 1 {
+        log_config
+            .set_thread_level(simplelog::LevelFilter::Error)
+            .set_thread_mode(simplelog::ThreadLogMode::Names);
+    }
+
+    // Keep stdout clean for the printed layers
+    let terminal_mode = match &args.command {
+        Some(Commands::Watch { .. }) => simplelog::TerminalMode::Stderr,
+        _ => simplelog::TerminalMode::Mixed,
+    };
+    // Let a reloaded config raise the level later, log::set_max_level still filters
     simplelog::TermLogger::init(
-        log_level,
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Mixed,
+        simplelog::LevelFilter::Trace,
+        log_config.build(),
+        terminal_mode,
         simplelog::ColorChoice::Auto,
     )
     .expect("failed to initialize logger");
+    log::set_max_level(settings.log_level);
 
-    // Get port (CLI overrides config)
-    let port = args.port.unwrap_or(config.port);
+    if args.command.is_some() && settings.instances.len() > 1 {
+        eprintln!("Several kanata instances are configured, pick one with --instance");
+        exit(1);
+    }
 
-    // Connect with retry logic
-    loop {
-        log::info!("attempting to connect to kanata on port {}", port);
-        match TcpStream::connect_timeout(
-            &SocketAddr::from(([127, 0, 0, 1], port)),
-            Duration::from_secs(5),
-        ) {
-            Ok(conn) => {
-                log::info!("successfully connected to kanata");
-                if let Err(e) = read_from_kanata(conn, &config.script_path) {
-                    log::error!("connection lost: {}. retrying in 30 seconds...", e);
-                    std::thread::sleep(Duration::from_secs(30));
-                }
-            }
-            Err(e) => {
-                log::error!(
-                    "failed to connect to kanata: {}. retrying in 30 seconds...",
-                    e
-                );
-                std::thread::sleep(Duration::from_secs(30));
+    match &args.command {
+        Some(Commands::Send { message }) => {
+            exit(send_command(settings.instances[0].addr.clone(), message))
+        }
+        Some(Commands::Watch { json, once, attach }) => {
+            let options = WatchOptions {
+                json: *json,
+                once: *once,
+            };
+            let instance = &settings.instances[0];
+            if !attach {
+                exit(watch::watch_kanata(
+                    instance.addr.clone(),
+                    settings.backoff.clone(),
+                    &options,
+                ));
             }
+            let Some(broadcast) = &instance.broadcast else {
+                eprintln!("--attach needs a [broadcast] table in the config");
+                exit(1);
+            };
+            exit(watch::watch_broadcast(broadcast, &options));
         }
+        Some(Commands::Check) | Some(Commands::Init { .. }) | None => {}
     }
+
+    // Bind before connecting so a taken socket or port is reported right away
+    let broadcasters: Vec<Option<Broadcaster>> = settings
+        .instances
+        .iter()
+        .map(|instance| {
+            instance
+                .broadcast
+                .as_ref()
+                .map(|broadcast| Broadcaster::start(&instance.name, broadcast))
+                .transpose()
+        })
+        .collect::<anyhow::Result<_>>()
+        .unwrap_or_else(|e| {
+            log::error!("{:#}", e);
+            exit(1);
+        });
+
+    // Each instance gets its own connection and reconnect state
+    let clients: Vec<KanataClient> = settings
+        .instances
+        .iter()
+        .map(|instance| {
+            KanataClient::new(instance.addr.clone()).with_backoff(settings.backoff.clone())
+        })
+        .collect();
+    let disconnect_handles = settings
+        .instances
+        .iter()
+        .zip(&clients)
+        .map(|(instance, client)| (instance.name.clone(), client.disconnect_handle()))
+        .collect();
+
+    let instances = settings.instances.clone();
+    let shared = SharedSettings::new(settings);
+    reload::spawn_watcher(
+        config_path,
+        shared.clone(),
+        disconnect_handles,
+        move |path| load_settings(path, read_config(path)?, &args),
+    );
+
+    std::thread::scope(|scope| {
+        for ((instance, broadcaster), client) in instances.iter().zip(&broadcasters).zip(clients) {
+            let shared = &shared;
+            std::thread::Builder::new()
+                .name(instance.name.clone())
+                .spawn_scoped(scope, move || {
+                    observer::run(shared, &instance.name, broadcaster.as_ref(), client)
+                })
+                .expect("failed to spawn reader thread");
+        }
+    });
+
+    log::error!("giving up on connecting to kanata");
+    exit(EXIT_RECONNECT_GAVE_UP);
 }
 
-fn read_from_kanata(s: TcpStream, script_path: &str) -> std::io::Result<()> {
-    log::debug!("reader starting");
-    let mut reader = BufReader::new(s);
-    let mut msg = String::new();
+/// Reads and parses the config file
+fn read_config(path: &str) -> anyhow::Result<Config> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path))?;
+    Config::parse(&contents, &config::env_overrides())
+        .with_context(|| format!("Failed to parse config file {}", path))
+}
 
-    // Expand ~ in script path
-    let expanded_script_path = shellexpand::tilde(script_path).to_string();
+/// Validates `config` and applies the CLI overrides
+fn load_settings(path: &str, config: Config, args: &Args) -> anyhow::Result<Settings> {
+    config
+        .validate()
+        .with_context(|| format!("Invalid config file {}", path))?;
 
-    loop {
-        msg.clear();
-        let bytes_read = reader.read_line(&mut msg)?;
-
-        // Connection closed
-        if bytes_read == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::ConnectionReset,
-                "connection closed by kanata",
-            ));
+    // Validated above
+    let mut instances = config.instances().expect("invalid instances config");
+    select_instances(&mut instances, args)?;
+    let layers = config.layer_handlers().expect("invalid layers config");
+    let backoff = config
+        .reconnect
+        .to_backoff()
+        .expect("invalid reconnect config");
+
+    // Determine log level (CLI overrides config)
+    let log_level = if args.trace {
+        log::LevelFilter::Trace
+    } else if args.debug {
+        log::LevelFilter::Debug
+    } else {
+        config.log_level.filter()
+    };
+
+    Ok(Settings {
+        config,
+        instances,
+        layers,
+        backoff,
+        log_level,
+    })
+}
+
+/// Applies `--instance`, `--host` and `--port` to the configured instances
+fn select_instances(instances: &mut Vec<Instance>, args: &Args) -> anyhow::Result<()> {
+    if let Some(name) = &args.instance {
+        instances.retain(|instance| &instance.name == name);
+        if instances.is_empty() {
+            anyhow::bail!("no kanata instance named {:?} in the config", name);
         }
+    }
+
+    if args.host.is_some() || args.port.is_some() {
+        let [instance] = instances.as_mut_slice() else {
+            anyhow::bail!(
+                "--host and --port need --instance when several kanata instances are configured"
+            );
+        };
+        // CLI overrides config
+        let host = args
+            .host
+            .clone()
+            .unwrap_or_else(|| instance.addr.host.clone());
+        let port = args.port.unwrap_or(instance.addr.port);
+        instance.addr = KanataAddr::new(host, port);
+    }
 
-        log::debug!("message received");
-
-        if let Ok(ServerMessage::LayerChange { new }) = serde_json::from_str::<ServerMessage>(&msg)
-        {
-            log::debug!("Layer changed to: {}", new);
-            let out = Command::new(&expanded_script_path).arg(&new).output();
-            match out {
-                Ok(output) => {
-                    if output.status.success() {
-                        log::debug!("Script executed successfully");
-                    } else {
-                        log::error!("Script failed: {}", String::from_utf8_lossy(&output.stderr));
-                    }
+    Ok(())
+}
+
+/// Sends a single command to kanata and waits for its reply, returning the exit code
+fn send_command(addr: KanataAddr, command: &SendCommand) -> i32 {
+    let result = KanataClient::connect(addr.clone()).and_then(|mut client| {
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        match command {
+            SendCommand::RequestLayerNames => {
+                client.send(&command.to_message())?;
+                print_layer_names(&mut client)
+            }
+            _ => client.request(&command.to_message()),
+        }
+    });
+
+    match result {
+        Ok(ServerResponse::Ok) => 0,
+        Ok(ServerResponse::Error { msg }) => {
+            eprintln!("kanata returned an error: {}", msg);
+            1
+        }
+        Err(e) => {
+            eprintln!("Failed to send command to kanata at {}: {}", addr, e);
+            1
+        }
+    }
+}
+
+fn print_layer_names(client: &mut KanataClient) -> std::io::Result<ServerResponse> {
+    loop {
+        // Kanata broadcasts layer changes to every client, so skip anything unrelated
+        match client.recv()? {
+            ServerMessage::LayerNames { names } => {
+                for name in names {
+                    println!("{}", name);
                 }
-                Err(e) => log::error!("Failed to execute script: {}", e),
+                return Ok(ServerResponse::Ok);
             }
+            ServerMessage::Error { msg } => return Ok(ServerResponse::Error { msg }),
+            _ => {}
         }
     }
 }
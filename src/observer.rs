@@ -0,0 +1,691 @@
+use crate::broadcast::Broadcaster;
+use crate::command::Action;
+use crate::config::{Instance, RuleConfig};
+use crate::event::{self, EventContext};
+use crate::layers::Rule;
+use crate::reload::{Settings, SharedSettings};
+use crate::script::{self, ScriptRunner, Task};
+use kanata_layer_observer::{ClientMessage, Event, KanataClient, ServerMessage};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Observes a single kanata instance until its client gives up reconnecting
+///
+/// The calling thread reads from kanata and re-broadcasts its messages while
+/// scripts are picked on a separate observer thread, so debouncing never delays
+/// reading.
+pub fn run(
+    shared: &SharedSettings,
+    name: &str,
+    broadcaster: Option<&Broadcaster>,
+    client: KanataClient,
+) {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        let observer = Observer::new(shared.clone(), name);
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn_scoped(scope, move || observer.run(receiver))
+            .expect("failed to spawn observer thread");
+
+        read_events(shared, name, client, broadcaster, sender);
+    });
+}
+
+/// Forwards kanata's messages to the observer thread, sending the requests that
+/// keep the layer state up to date
+fn read_events(
+    shared: &SharedSettings,
+    name: &str,
+    mut client: KanataClient,
+    broadcaster: Option<&Broadcaster>,
+    sender: Sender<ServerMessage>,
+) {
+    while let Some(event) = client.next() {
+        // Pick up a reloaded config, reconnecting if the instance moved
+        let settings = shared.get();
+        client.set_backoff(settings.backoff.clone());
+        if let Some(instance) = settings.instance(name) {
+            if *client.addr() != instance.addr {
+                client.set_addr(instance.addr.clone());
+                continue;
+            }
+        }
+
+        match event {
+            Event::Connected => {
+                log::info!("successfully connected to kanata");
+                // Ask for the layer list and the current layer so scripts don't wait for the next change
+                let requested = client
+                    .send(&ClientMessage::RequestLayerNames {})
+                    .and_then(|()| client.send(&ClientMessage::RequestCurrentLayerName {}));
+                if let Err(e) = requested {
+                    log::error!("failed to request the current layer: {}", e);
+                }
+            }
+            Event::Message(msg) => {
+                if let ServerMessage::ConfigFileReload { .. } = msg {
+                    // Layers may have been added, removed or reordered
+                    if let Err(e) = client.send(&ClientMessage::RequestLayerNames {}) {
+                        log::error!("failed to request layer names: {}", e);
+                    }
+                }
+                if let Some(broadcaster) = broadcaster {
+                    broadcaster.send(&msg);
+                }
+                if sender.send(msg).is_err() {
+                    log::error!("observer thread stopped");
+                    return;
+                }
+            }
+            Event::ConnectFailed {
+                error,
+                retry_in: Some(delay),
+            } => {
+                log::error!(
+                    "failed to connect to kanata: {}. retrying in {:.1} seconds...",
+                    error,
+                    delay.as_secs_f64()
+                );
+            }
+            Event::ConnectFailed {
+                error,
+                retry_in: None,
+            } => {
+                log::error!("failed to connect to kanata: {}", error);
+            }
+            Event::Disconnected {
+                error,
+                retry_in: Some(delay),
+            } if delay.is_zero() => {
+                log::info!("{}. reconnecting...", error);
+            }
+            Event::Disconnected {
+                error,
+                retry_in: Some(delay),
+            } => {
+                log::error!(
+                    "connection lost: {}. retrying in {:.1} seconds...",
+                    error,
+                    delay.as_secs_f64()
+                );
+            }
+            Event::Disconnected {
+                error,
+                retry_in: None,
+            } => {
+                log::error!("connection lost: {}", error);
+            }
+        }
+    }
+}
+
+/// A layer change waiting for the layer to be stable for `debounce_ms`
+struct PendingChange {
+    layer: String,
+    event: &'static str,
+    timestamp: u64,
+    deadline: Instant,
+}
+
+impl PendingChange {
+    /// Whether the layer bounced back to `current` while debouncing; the initial
+    /// layer still runs its scripts after a reconnect
+    fn is_noop(&self, current: Option<&str>) -> bool {
+        self.event != "initial" && current == Some(self.layer.as_str())
+    }
+}
+
+/// Runs scripts for the messages of a single kanata instance
+struct Observer {
+    shared: SharedSettings,
+    /// Snapshot of `shared` used until the next message
+    settings: Arc<Settings>,
+    /// Name of the observed instance in `settings.instances`
+    name: String,
+    runner: ScriptRunner,
+    /// Layer names as last reported by kanata, in kanata's order
+    layer_names: Vec<String>,
+    /// The current layer, kept across reconnects
+    layer: Option<String>,
+    /// The layer before `layer`, if known
+    prev_layer: Option<String>,
+    /// Which of `settings.layers.rules` have been entered and not exited yet
+    active_rules: Vec<bool>,
+    pending: Option<PendingChange>,
+}
+
+impl Observer {
+    fn new(shared: SharedSettings, name: &str) -> Self {
+        let settings = shared.get();
+        Observer {
+            runner: ScriptRunner::spawn(name, settings.config.script_timeout()),
+            active_rules: vec![false; settings.layers.rules.len()],
+            shared,
+            settings,
+            name: name.to_string(),
+            layer_names: Vec::new(),
+            layer: None,
+            prev_layer: None,
+            pending: None,
+        }
+    }
+
+    fn instance(&self) -> &Instance {
+        self.settings
+            .instance(&self.name)
+            .expect("instances don't change on reload")
+    }
+
+    /// Switches to the latest settings if the config was reloaded
+    fn refresh(&mut self) {
+        let settings = self.shared.get();
+        if Arc::ptr_eq(&settings, &self.settings) {
+            return;
+        }
+        let old = std::mem::replace(&mut self.settings, settings);
+        self.runner
+            .set_timeout(self.settings.config.script_timeout());
+
+        // Only rules that were added or changed by the reload need a new state,
+        // otherwise `on_exit` could run for a rule whose `on_enter` never did
+        let rules = &self.settings.layers.rules;
+        self.active_rules = reloaded_rules(
+            &old.config.rules,
+            &self.active_rules,
+            &self.settings.config.rules,
+            |i| {
+                self.layer.as_deref().is_some_and(|layer| {
+                    rules[i].enters(self.prev_layer.as_deref(), layer) && rules[i].stays_in(layer)
+                })
+            },
+        );
+    }
+
+    /// Handles messages until the reader hangs up
+    fn run(mut self, receiver: Receiver<ServerMessage>) {
+        loop {
+            let msg = match &self.pending {
+                Some(pending) => {
+                    let timeout = pending.deadline.saturating_duration_since(Instant::now());
+                    match receiver.recv_timeout(timeout) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.refresh();
+                            self.fire_pending();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+            };
+            self.refresh();
+            self.handle_message(msg);
+        }
+    }
+
+    fn handle_message(&mut self, msg: ServerMessage) {
+        log::debug!("message received");
+        let timestamp = event::timestamp();
+
+        match msg {
+            ServerMessage::LayerChange { new } => {
+                log::debug!("Layer changed to: {}", new);
+                if !self.layer_names.is_empty() && !self.layer_names.contains(&new) {
+                    log::warn!("layer {} is not in kanata's layer list", new);
+                }
+                self.queue_layer_change(new, "layer_change", timestamp);
+            }
+            ServerMessage::CurrentLayerName { name } => {
+                log::debug!("Initial layer: {}", name);
+                self.queue_layer_change(name, "initial", timestamp);
+            }
+            ServerMessage::LayerNames { names } => {
+                log::debug!("Layer names: {:?}", names);
+                self.layer_names = names;
+            }
+            ServerMessage::ConfigFileReload { new } => {
+                log::debug!("Kanata config reloaded: {}", new);
+                if let Some(script_path) = &self.instance().on_config_reload {
+                    let mut context = self.context("config_reload", timestamp);
+                    context.config_path = Some(new.clone());
+                    self.run_tasks(vec![Task::script(script_path, &[&new])], &context);
+                }
+            }
+            ServerMessage::MessagePush { message } => {
+                log::debug!("Message pushed: {}", message);
+                if let Some(script_path) = self.settings.config.message_push.script_for(&message) {
+                    self.run_message_push_script(script_path, message, timestamp);
+                }
+            }
+            other => log::trace!("ignoring message: {:?}", other),
+        }
+    }
+
+    /// Switches to `layer` now, or once it has been stable for `debounce_ms`
+    fn queue_layer_change(&mut self, layer: String, event: &'static str, timestamp: u64) {
+        let debounce = Duration::from_millis(self.settings.config.debounce_ms);
+        if debounce.is_zero() {
+            self.change_layer(layer, event, timestamp);
+            return;
+        }
+
+        // An initial layer replaced before firing is still a change from the scripts' view
+        let event = match &self.pending {
+            Some(pending) if pending.event != event => "layer_change",
+            _ => event,
+        };
+        self.pending = Some(PendingChange {
+            layer,
+            event,
+            timestamp,
+            deadline: Instant::now() + debounce,
+        });
+    }
+
+    fn fire_pending(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        if pending.is_noop(self.layer.as_deref()) {
+            log::debug!("layer {} unchanged after debouncing", pending.layer);
+            return;
+        }
+        self.change_layer(pending.layer, pending.event, pending.timestamp);
+    }
+
+    /// Runs the scripts for switching to `layer`: `on_exit` of rules that no longer
+    /// match, then the layer's own scripts, then `on_enter` of newly matching rules
+    fn change_layer(&mut self, layer: String, event: &'static str, timestamp: u64) {
+        // Only changes that survived debouncing replace the running scripts
+        if self.settings.config.latest_wins {
+            self.runner.cancel();
+        }
+
+        let prev = self.layer.replace(layer.clone());
+        // Reconnecting reports the same layer again, which doesn't leave it
+        if prev.as_ref() != Some(&layer) {
+            self.prev_layer.clone_from(&prev);
+        }
+
+        // $1 is the new layer, $2 the previous one if known
+        let args: Vec<&str> = std::iter::once(layer.as_str())
+            .chain(prev.as_deref())
+            .collect();
+        let exit_context = self.layer_context("exit", prev.as_deref(), timestamp);
+        let layer_context = self.layer_context(event, prev.as_deref(), timestamp);
+        let enter_context = self.layer_context("enter", prev.as_deref(), timestamp);
+
+        let settings = Arc::clone(&self.settings);
+        let rules = &settings.layers.rules;
+        let (exited, entered) =
+            rule_transitions(rules, &mut self.active_rules, prev.as_deref(), &layer);
+        for i in exited {
+            self.run_actions(&rules[i].on_exit, &args, &exit_context);
+        }
+
+        let actions = match settings.layers.get(&layer) {
+            Some(handler) => &handler.commands,
+            None => &self.instance().commands,
+        };
+        if actions.is_empty() {
+            log::debug!("no script for layer {}", layer);
+        }
+        self.run_actions(actions, &args, &layer_context);
+
+        for i in entered {
+            self.run_actions(&rules[i].on_enter, &args, &enter_context);
+        }
+    }
+
+    fn run_message_push_script(
+        &self,
+        script_path: &str,
+        message: serde_json::Value,
+        timestamp: u64,
+    ) {
+        let mut envs = self.context("message_push", timestamp).envs();
+
+        // Flatten top-level fields into environment variables
+        if let serde_json::Value::Object(fields) = &message {
+            for (key, value) in fields {
+                envs.push((script::message_env_var(key), script::json_to_string(value)));
+            }
+        }
+
+        // Pushed messages always get the raw message on stdin
+        self.runner.run(
+            vec![Task::script(script_path, &[])],
+            &envs,
+            Some(message.to_string().as_bytes()),
+        );
+    }
+
+    /// Queues `actions`, passing `script_args` to scripts
+    fn run_actions(&self, actions: &[Action], script_args: &[&str], context: &EventContext) {
+        let tasks = actions
+            .iter()
+            .map(|action| action.task(script_args, context))
+            .collect();
+        self.run_tasks(tasks, context);
+    }
+
+    /// Queues `tasks`, passing `context` in the environment and, with `stdin_json`,
+    /// on stdin
+    fn run_tasks(&self, tasks: Vec<Task>, context: &EventContext) {
+        let stdin = self.settings.config.stdin_json.then(|| context.to_json());
+        self.runner
+            .run(tasks, &context.envs(), stdin.as_deref().map(str::as_bytes));
+    }
+
+    fn layer_context(
+        &self,
+        event: &'static str,
+        prev: Option<&str>,
+        timestamp: u64,
+    ) -> EventContext {
+        EventContext {
+            prev_layer: prev.map(str::to_string),
+            ..self.context(event, timestamp)
+        }
+    }
+
+    /// Context for `event` with the current layer filled in
+    fn context(&self, event: &'static str, timestamp: u64) -> EventContext {
+        let layer_index = self
+            .layer
+            .as_ref()
+            .and_then(|layer| self.layer_names.iter().position(|name| name == layer));
+        let instance = self.instance();
+        EventContext {
+            event,
+            instance: instance.name.clone(),
+            host: instance.addr.host.clone(),
+            port: instance.addr.port,
+            timestamp,
+            layer: self.layer.clone(),
+            display_name: self
+                .layer
+                .as_ref()
+                .and_then(|layer| self.settings.layers.get(layer))
+                .and_then(|handler| handler.display_name.clone()),
+            prev_layer: None,
+            layer_index,
+            layer_count: (!self.layer_names.is_empty()).then_some(self.layer_names.len()),
+            config_path: None,
+        }
+    }
+}
+
+/// Updates which `rules` are `active` for a change from `prev` to `layer`, returning
+/// the rules that were exited and those that were entered, in order
+fn rule_transitions(
+    rules: &[Rule],
+    active: &mut [bool],
+    prev: Option<&str>,
+    layer: &str,
+) -> (Vec<usize>, Vec<usize>) {
+    let exited: Vec<usize> = (0..rules.len())
+        .filter(|&i| active[i] && !rules[i].stays_in(layer))
+        .collect();
+    for &i in &exited {
+        active[i] = false;
+    }
+    let entered: Vec<usize> = (0..rules.len())
+        .filter(|&i| !active[i] && rules[i].enters(prev, layer))
+        .collect();
+    for &i in &entered {
+        active[i] = rules[i].stays_in(layer);
+    }
+    (exited, entered)
+}
+
+/// Returns which of the reloaded `new` rules are active: rules also in `old` keep
+/// their state in `active`, others are active if `entered` by the last layer change
+fn reloaded_rules(
+    old: &[RuleConfig],
+    active: &[bool],
+    new: &[RuleConfig],
+    mut entered: impl FnMut(usize) -> bool,
+) -> Vec<bool> {
+    let mut carried = vec![false; old.len()];
+    new.iter()
+        .enumerate()
+        .map(|(i, rule)| {
+            let same = (0..old.len()).find(|&j| !carried[j] && old[j] == *rule);
+            match same {
+                Some(j) => {
+                    carried[j] = true;
+                    active[j]
+                }
+                None => entered(i),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, DEFAULT_INSTANCE};
+    use crate::layers::LayerHandlers;
+
+    fn pending(layer: &str, event: &'static str) -> PendingChange {
+        PendingChange {
+            layer: layer.to_string(),
+            event,
+            timestamp: 0,
+            deadline: Instant::now(),
+        }
+    }
+
+    fn rule(from: Option<&str>, to: &str) -> RuleConfig {
+        RuleConfig {
+            from: from.map(str::to_string),
+            to: Some(to.to_string()),
+            on_enter: Vec::new(),
+            on_exit: Vec::new(),
+        }
+    }
+
+    /// Changes through `layers` from an unknown one, returning the rule indexes each
+    /// change exited and entered
+    fn transitions(rules: &[RuleConfig], layers: &[&str]) -> Vec<(Vec<usize>, Vec<usize>)> {
+        let rules = LayerHandlers::new(&Default::default(), rules)
+            .unwrap()
+            .rules;
+        let mut active = vec![false; rules.len()];
+        let mut prev = None;
+        layers
+            .iter()
+            .map(|&layer| {
+                let changes = rule_transitions(&rules, &mut active, prev, layer);
+                prev = Some(layer);
+                changes
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rules_without_to_enter_on_every_change_from_their_layer() {
+        let left_gaming = RuleConfig {
+            to: None,
+            ..rule(Some("gaming"), "")
+        };
+        let layers = ["gaming", "base", "gaming", "nav", "gaming", "base"];
+        let entered: Vec<_> = transitions(&[left_gaming], &layers)
+            .into_iter()
+            .map(|(exited, entered)| {
+                assert!(exited.is_empty());
+                !entered.is_empty()
+            })
+            .collect();
+        assert_eq!(entered, [false, true, false, true, false, true]);
+    }
+
+    #[test]
+    fn rules_with_to_stay_active_until_left() {
+        let rules = [rule(None, "gaming"), rule(Some("base"), "nav-*")];
+        let layers = [
+            "base",
+            "gaming",
+            "gaming",
+            "nav-left",
+            "base",
+            "nav-left",
+            "nav-right",
+            "base",
+        ];
+        let none = Vec::<usize>::new;
+        assert_eq!(
+            transitions(&rules, &layers),
+            [
+                (none(), none()),
+                (none(), vec![0]),
+                (none(), none()),
+                (vec![0], none()),
+                (none(), none()),
+                (none(), vec![1]),
+                (none(), none()),
+                (vec![1], none()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_ignore_changes_to_the_same_layer() {
+        let rules = [
+            RuleConfig {
+                to: None,
+                ..rule(Some("gaming"), "")
+            },
+            RuleConfig {
+                to: None,
+                ..rule(None, "")
+            },
+        ];
+        let changes = transitions(&rules, &["gaming", "gaming"]);
+        // The initial layer only enters rules without `from`
+        assert_eq!(changes[0], (vec![], vec![1]));
+        assert_eq!(changes[1], (vec![], vec![]));
+    }
+
+    #[test]
+    fn unchanged_rules_keep_their_state() {
+        let rules = [rule(Some("base"), "gaming"), rule(None, "nav")];
+        let same = [rule(Some("base"), "gaming"), rule(None, "nav")];
+        let active = reloaded_rules(&rules, &[false, true], &same, |_| {
+            panic!("unchanged rules must not be re-evaluated")
+        });
+        assert_eq!(active, [false, true]);
+    }
+
+    #[test]
+    fn reordered_rules_keep_their_state() {
+        let rules = [rule(Some("base"), "gaming"), rule(None, "nav")];
+        let reordered = [rule(None, "nav"), rule(Some("base"), "gaming")];
+        let active = reloaded_rules(&rules, &[true, false], &reordered, |_| unreachable!());
+        assert_eq!(active, [false, true]);
+    }
+
+    #[test]
+    fn changed_and_added_rules_use_the_last_change() {
+        let rules = [rule(None, "nav")];
+        let new = [
+            rule(None, "nav-*"),
+            rule(Some("base"), "gaming"),
+            rule(None, "nav"),
+        ];
+        // Entered: only the first new rule, as if the layer went from nav to nav-left
+        let active = reloaded_rules(&rules, &[true], &new, |i| i == 0);
+        assert_eq!(active, [true, false, true]);
+    }
+
+    #[test]
+    fn duplicate_rules_are_matched_once() {
+        let rules = [rule(None, "nav")];
+        let new = [rule(None, "nav"), rule(None, "nav")];
+        let active = reloaded_rules(&rules, &[true], &new, |_| false);
+        assert_eq!(active, [true, false]);
+    }
+
+    #[test]
+    fn bounce_back_to_current_layer_is_noop() {
+        assert!(pending("base", "layer_change").is_noop(Some("base")));
+        assert!(!pending("nav", "layer_change").is_noop(Some("base")));
+    }
+
+    #[test]
+    fn first_layer_is_never_noop() {
+        assert!(!pending("base", "layer_change").is_noop(None));
+        assert!(!pending("base", "initial").is_noop(None));
+    }
+
+    #[test]
+    fn initial_layer_runs_again_after_reconnect() {
+        assert!(!pending("base", "initial").is_noop(Some("base")));
+    }
+
+    /// Observer for the default instance, running `script` with `sh -c` on every change
+    #[cfg(unix)]
+    fn observer(settings: &str, script: &str) -> Observer {
+        let mut config: Config = toml::from_str(settings).unwrap();
+        config.command = Some(vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            script.to_string(),
+        ]);
+        let settings = Settings {
+            instances: config.instances().unwrap(),
+            layers: config.layer_handlers().unwrap(),
+            backoff: Default::default(),
+            log_level: log::LevelFilter::Off,
+            config,
+        };
+        Observer::new(SharedSettings::new(settings), DEFAULT_INSTANCE)
+    }
+
+    /// Changes to `base`, then queues `blip` and `last`, returning the layers whose
+    /// scripts ran to completion
+    #[cfg(unix)]
+    fn run_latest_wins(name: &str, blip: &str, last: &str) -> Vec<String> {
+        let dir =
+            std::env::temp_dir().join(format!("kanata-observer-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = "port = 1\ndebounce_ms = 50\nlatest_wins = true";
+        let script = format!("sleep 0.3; touch '{}'/{{layer}}", dir.display());
+        let mut observer = observer(settings, &script);
+        observer.change_layer("base".to_string(), "layer_change", 0);
+        std::thread::sleep(Duration::from_millis(50));
+        observer.queue_layer_change(blip.to_string(), "layer_change", 0);
+        observer.queue_layer_change(last.to_string(), "layer_change", 0);
+        observer.fire_pending();
+        // Waits for the queued scripts
+        drop(observer);
+
+        let mut ran: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        ran.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        ran
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn latest_wins_ignores_changes_dropped_by_debouncing() {
+        assert_eq!(run_latest_wins("blip", "nav", "base"), ["base"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn latest_wins_cancels_for_changes_that_fire() {
+        assert_eq!(run_latest_wins("fire", "nav", "gaming"), ["gaming"]);
+    }
+}
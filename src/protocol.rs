@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+/// Messages sent by kanata's TCP server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMessage {
+    LayerChange { new: String },
+    LayerNames { names: Vec<String> },
+    CurrentLayerInfo { name: String, cfg_text: String },
+    ConfigFileReload { new: String },
+    CurrentLayerName { name: String },
+    MessagePush { message: serde_json::Value },
+    Error { msg: String },
+}
+
+/// Messages accepted by kanata's TCP server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientMessage {
+    ChangeLayer {
+        new: String,
+    },
+    RequestLayerNames {},
+    RequestCurrentLayerInfo {},
+    RequestCurrentLayerName {},
+    ActOnFakeKey {
+        name: String,
+        action: FakeKeyActionMessage,
+    },
+    SetMouse {
+        x: u16,
+        y: u16,
+    },
+    Reload {},
+    ReloadNext {},
+    ReloadPrev {},
+    ReloadNum {
+        index: usize,
+    },
+    ReloadFile {
+        path: String,
+    },
+}
+
+impl ClientMessage {
+    /// Serializes the message as a newline-terminated JSON line
+    pub(crate) fn to_line(&self) -> serde_json::Result<String> {
+        let mut line = serde_json::to_string(self)?;
+        log::trace!("sending: {}", line);
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FakeKeyActionMessage {
+    Press,
+    Release,
+    Tap,
+    Toggle,
+}
+
+/// Kanata's reply to a command sent by a client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status")]
+pub enum ServerResponse {
+    Ok,
+    Error { msg: String },
+}
@@ -0,0 +1,139 @@
+use crate::config::{Config, Instance};
+use crate::layers::LayerHandlers;
+use kanata_layer_observer::{Backoff, DisconnectHandle};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How often to check whether the config file changed or SIGHUP was received
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The config file and everything derived from it, replaced as a whole on reload
+pub struct Settings {
+    pub config: Config,
+    /// Instances to observe, with CLI overrides applied
+    pub instances: Vec<Instance>,
+    pub layers: LayerHandlers,
+    pub backoff: Backoff,
+    /// Log level, with CLI overrides applied
+    pub log_level: log::LevelFilter,
+}
+
+impl Settings {
+    pub fn instance(&self, name: &str) -> Option<&Instance> {
+        self.instances.iter().find(|instance| instance.name == name)
+    }
+}
+
+/// The current [`Settings`], shared by every thread
+///
+/// Threads take a snapshot with [`SharedSettings::get`] per event, so an event is
+/// always handled with either the old or the new config, never a mix.
+#[derive(Clone)]
+pub struct SharedSettings(Arc<Mutex<Arc<Settings>>>);
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        SharedSettings(Arc::new(Mutex::new(Arc::new(settings))))
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        Arc::clone(&self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn replace(&self, settings: Arc<Settings>) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = settings;
+    }
+}
+
+/// Reloads the config file at `path` with `load` whenever it changes or the process
+/// receives SIGHUP
+///
+/// Clients are only disconnected, through `clients`, if their instance's address
+/// changed. If the new config is invalid the old one stays in use.
+pub fn spawn_watcher(
+    path: String,
+    shared: SharedSettings,
+    clients: HashMap<String, DisconnectHandle>,
+    load: impl Fn(&str) -> anyhow::Result<Settings> + Send + 'static,
+) {
+    let hangup = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&hangup)) {
+        log::error!("failed to handle SIGHUP, only reloading on changes: {}", e);
+    }
+
+    std::thread::Builder::new()
+        .name("config".to_string())
+        .spawn(move || {
+            let mut last_modified = modified(&path);
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+                let hangup = hangup.swap(false, Ordering::SeqCst);
+                let now = modified(&path);
+                // A missing file is usually an editor replacing it, wait for the new one
+                let changed = now.is_some() && now != last_modified;
+                last_modified = now;
+                if hangup || changed {
+                    reload(&path, &shared, &clients, &load);
+                }
+            }
+        })
+        .expect("failed to spawn config watcher thread");
+}
+
+fn reload(
+    path: &str,
+    shared: &SharedSettings,
+    clients: &HashMap<String, DisconnectHandle>,
+    load: &impl Fn(&str) -> anyhow::Result<Settings>,
+) {
+    let new = match load(path) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("{:#}, keeping the previous config", e);
+            return;
+        }
+    };
+    let old = shared.get();
+
+    // Connections and broadcast sockets are set up per instance at startup
+    let unchanged = |old: &Instance| {
+        new.instance(&old.name)
+            .is_some_and(|new| new.broadcast == old.broadcast)
+    };
+    if new.instances.len() != old.instances.len() || !old.instances.iter().all(unchanged) {
+        log::error!(
+            "adding, removing or renaming [[kanata]] instances and changing [broadcast] needs a restart, keeping the previous config"
+        );
+        return;
+    }
+
+    log::set_max_level(new.log_level);
+    let new = Arc::new(new);
+    shared.replace(Arc::clone(&new));
+    log::info!("reloaded config file {}", path);
+
+    for instance in &new.instances {
+        let moved = old
+            .instance(&instance.name)
+            .is_some_and(|old| old.addr != instance.addr);
+        if moved {
+            log::info!(
+                "kanata instance {} moved to {}, reconnecting",
+                instance.name,
+                instance.addr
+            );
+            if let Some(client) = clients.get(&instance.name) {
+                client.disconnect();
+            }
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
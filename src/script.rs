@@ -0,0 +1,666 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often to check whether a running script has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for the rest of a failed script's stderr after it exited
+const STDERR_WAIT: Duration = Duration::from_millis(100);
+
+/// A script, file to write, or group of those to run for an event
+#[derive(Debug)]
+pub struct Task {
+    pub kind: TaskKind,
+    /// Skip the tasks after this one if it fails
+    pub stop_on_error: bool,
+}
+
+#[derive(Debug)]
+pub enum TaskKind {
+    Script {
+        path: String,
+        args: Vec<String>,
+    },
+    /// Tasks run in order or, if `parallel`, all at once; fails if any of them fails
+    Group {
+        tasks: Vec<Task>,
+        parallel: bool,
+    },
+    /// Replaces the file at `path` without spawning a process
+    WriteFile {
+        path: String,
+        contents: String,
+    },
+}
+
+impl Task {
+    pub fn script(path: &str, args: &[&str]) -> Self {
+        Task {
+            kind: TaskKind::Script {
+                path: path.to_string(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+            },
+            stop_on_error: false,
+        }
+    }
+}
+
+/// Tasks for one event queued on a [`ScriptRunner`]
+struct Job {
+    tasks: Vec<Task>,
+    envs: Vec<(String, String)>,
+    stdin: Option<Vec<u8>>,
+    /// Scripts running longer than this are killed
+    timeout: Option<Duration>,
+    /// [`ScriptRunner::cancel`] generation the job was queued in
+    generation: u64,
+}
+
+/// Runs scripts one event at a time on a worker thread, so a slow or hung script
+/// never blocks reading from kanata
+pub struct ScriptRunner {
+    sender: Option<mpsc::Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+    /// Bumped by [`ScriptRunner::cancel`]; jobs from older generations are skipped or killed
+    generation: Arc<AtomicU64>,
+    timeout: Option<Duration>,
+}
+
+impl ScriptRunner {
+    /// Starts the worker thread; scripts running longer than `timeout` are killed
+    pub fn spawn(name: &str, timeout: Option<Duration>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let generation = Arc::new(AtomicU64::new(0));
+        let current = Arc::clone(&generation);
+        let worker = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                for job in receiver {
+                    if job.cancelled(&current) {
+                        log::debug!("skipping cancelled scripts");
+                    } else {
+                        job.run_all(&job.tasks, job.timeout, &current);
+                    }
+                }
+            })
+            .expect("failed to spawn script runner thread");
+
+        ScriptRunner {
+            sender: Some(sender),
+            worker: Some(worker),
+            generation,
+            timeout,
+        }
+    }
+
+    /// Sets the timeout for scripts queued from now on
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Kills the running script and skips every script queued so far
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Queues `tasks` with extra environment variables, optionally writing `stdin`
+    /// to the standard input of every script
+    pub fn run(&self, tasks: Vec<Task>, envs: &[(String, String)], stdin: Option<&[u8]>) {
+        if tasks.is_empty() {
+            return;
+        }
+        let job = Job {
+            tasks,
+            envs: envs.to_vec(),
+            stdin: stdin.map(<[u8]>::to_vec),
+            timeout: self.timeout,
+            generation: self.generation.load(Ordering::SeqCst),
+        };
+        if let Some(sender) = &self.sender {
+            // Only fails if the worker panicked
+            if sender.send(job).is_err() {
+                log::error!("script runner stopped, not running scripts");
+            }
+        }
+    }
+}
+
+impl Drop for ScriptRunner {
+    /// Lets queued scripts finish before returning
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Job {
+    fn cancelled(&self, generation: &AtomicU64) -> bool {
+        generation.load(Ordering::SeqCst) != self.generation
+    }
+
+    /// Runs `tasks` in order, returning whether all of them succeeded
+    fn run_all(&self, tasks: &[Task], timeout: Option<Duration>, generation: &AtomicU64) -> bool {
+        let mut success = true;
+        for task in tasks {
+            if self.cancelled(generation) {
+                return false;
+            }
+            if !self.run_task(task, timeout, generation) {
+                success = false;
+                if task.stop_on_error {
+                    log::debug!("skipping the remaining scripts after a failure");
+                    return false;
+                }
+            }
+        }
+        success
+    }
+
+    fn run_task(&self, task: &Task, timeout: Option<Duration>, generation: &AtomicU64) -> bool {
+        match &task.kind {
+            TaskKind::Script { path, args } => self.run_script(path, args, timeout, generation),
+            TaskKind::WriteFile { path, contents } => match write_file(path, contents) {
+                Ok(()) => {
+                    log::debug!("Wrote {}", path);
+                    true
+                }
+                Err(e) => {
+                    log::error!("Failed to write {}: {}", path, e);
+                    false
+                }
+            },
+            TaskKind::Group {
+                tasks,
+                parallel: false,
+            } => self.run_all(tasks, timeout, generation),
+            TaskKind::Group {
+                tasks,
+                parallel: true,
+            } => std::thread::scope(|scope| {
+                let running: Vec<_> = tasks
+                    .iter()
+                    .map(|task| scope.spawn(|| self.run_task(task, timeout, generation)))
+                    .collect();
+                // Join every thread before looking at the results
+                let results: Vec<bool> = running
+                    .into_iter()
+                    .map(|thread| thread.join().unwrap_or(false))
+                    .collect();
+                results.into_iter().all(|success| success)
+            }),
+        }
+    }
+
+    /// Runs a single script, returning whether it exited successfully
+    fn run_script(
+        &self,
+        script_path: &str,
+        args: &[String],
+        timeout: Option<Duration>,
+        generation: &AtomicU64,
+    ) -> bool {
+        let mut command = script_command(script_path);
+        command
+            .args(args)
+            .envs(self.envs.iter().map(|(key, value)| (key, value)))
+            .stdin(if self.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        // Put the script in its own process group so a timeout also kills anything it started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                log::error!("Failed to execute script {}: {}", script_path, e);
+                return false;
+            }
+        };
+
+        // Feed stdin and drain stderr on their own threads so a script that doesn't
+        // read its input or writes lots of output can't deadlock the wait below
+        if let (Some(input), Some(mut pipe)) = (self.stdin.clone(), child.stdin.take()) {
+            std::thread::spawn(move || {
+                if let Err(e) = pipe.write_all(&input) {
+                    log::debug!("Failed to write script stdin: {}", e);
+                }
+            });
+        }
+        let stderr = child.stderr.take().map(read_chunks);
+
+        let cancelled = || self.cancelled(generation);
+        let status = wait_timeout(&mut child, timeout, cancelled);
+
+        match status {
+            Ok(Some(status)) if status.success() => {
+                log::debug!("Script executed successfully");
+                return true;
+            }
+            Ok(Some(_)) => {
+                // Processes the script left running may keep stderr open, so only wait
+                // briefly for what the script itself wrote
+                let stderr = stderr
+                    .map(|chunks| collect_chunks(&chunks, STDERR_WAIT))
+                    .unwrap_or_default();
+                log::error!("Script failed: {}", String::from_utf8_lossy(&stderr))
+            }
+            Ok(None) if cancelled() => {
+                log::debug!("Script {} cancelled by a newer layer change", script_path)
+            }
+            Ok(None) => log::error!(
+                "Script {} timed out after {:.1} seconds and was killed",
+                script_path,
+                timeout.unwrap_or_default().as_secs_f64()
+            ),
+            Err(e) => log::error!("Failed to execute script {}: {}", script_path, e),
+        }
+        false
+    }
+}
+
+/// Waits for `child` to exit, killing it once `timeout` has passed or `cancelled`
+/// returns true
+///
+/// Returns `None` if the child was killed.
+fn wait_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    cancelled: impl Fn() -> bool,
+) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if cancelled() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill(child)?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Reads `pipe` on its own thread until it's closed, sending what was read in chunks
+fn read_chunks(mut pipe: impl Read + Send + 'static) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        // Keep reading after the receiver is gone so writers never block on a full pipe
+        while let Ok(read @ 1..) = pipe.read(&mut buffer) {
+            let _ = sender.send(buffer[..read].to_vec());
+        }
+    });
+    receiver
+}
+
+/// Returns the chunks received until the sender hangs up or `timeout` has passed
+fn collect_chunks(chunks: &mpsc::Receiver<Vec<u8>>, timeout: Duration) -> Vec<u8> {
+    let deadline = Instant::now() + timeout;
+    let mut output = Vec::new();
+    while let Ok(chunk) = chunks.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        output.extend(chunk);
+    }
+    output
+}
+
+/// Kills `child` and, on unix, the rest of its process group
+fn kill(child: &mut Child) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Ok(pgid) = libc::pid_t::try_from(child.id()) {
+        // SAFETY: killpg has no memory safety requirements
+        unsafe { libc::killpg(pgid, libc::SIGKILL) };
+    }
+    child.kill()
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`,
+/// so readers never see a partially written file
+fn write_file(path: &str, contents: &str) -> std::io::Result<()> {
+    // Parallel groups may write the same file at once, so every write gets its own temporary file
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let path = PathBuf::from(shellexpand::tilde(path).as_ref());
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_path = path.with_file_name(temp_name);
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, &path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp_path);
+    })
+}
+
+fn script_command(script_path: &str) -> Command {
+    // Expand ~ in script path
+    Command::new(shellexpand::tilde(script_path).as_ref())
+}
+
+/// Converts a pushed message field name into `KANATA_MSG_<FIELD>`
+pub fn message_env_var(key: &str) -> String {
+    let field: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("KANATA_MSG_{}", field)
+}
+
+/// Strings are passed through unquoted, everything else as JSON
+pub fn json_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn job() -> Job {
+        Job {
+            tasks: Vec::new(),
+            envs: Vec::new(),
+            stdin: None,
+            timeout: None,
+            generation: 0,
+        }
+    }
+
+    fn sh(script: &str) -> Task {
+        Task::script("/bin/sh", &["-c", script])
+    }
+
+    /// Creates an empty directory for one test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "kanata-observer-script-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs `task`, returning whether it succeeded and how long it took
+    fn run(task: &Task, timeout: Option<Duration>) -> (bool, Duration) {
+        let start = Instant::now();
+        let success = job().run_task(task, timeout, &AtomicU64::new(0));
+        (success, start.elapsed())
+    }
+
+    #[test]
+    fn script_exit_status_is_the_result() {
+        assert!(run(&sh("exit 0"), None).0);
+        assert!(!run(&sh("echo oops >&2; exit 3"), None).0);
+        assert!(!run(&Task::script("/nonexistent/script", &[]), None).0);
+    }
+
+    #[test]
+    fn script_is_killed_after_timeout() {
+        let (success, elapsed) = run(&sh("sleep 5"), Some(Duration::from_millis(200)));
+        assert!(!success);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn timeout_kills_processes_the_script_started() {
+        let dir = temp_dir("kill");
+        let marker = dir.join("marker");
+        let script = format!("(sleep 0.5; touch {}) & wait", marker.display());
+        assert!(!run(&sh(&script), Some(Duration::from_millis(100))).0);
+        std::thread::sleep(Duration::from_millis(800));
+        assert!(!marker.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn background_processes_do_not_delay_the_next_script() {
+        let timeout = Some(Duration::from_secs(1));
+        let (success, elapsed) = run(&sh("sleep 3 & exit 0"), timeout);
+        assert!(success);
+        assert!(elapsed < Duration::from_millis(800), "took {:?}", elapsed);
+
+        let (success, elapsed) = run(&sh("echo oops >&2; sleep 3 & exit 1"), timeout);
+        assert!(!success);
+        assert!(elapsed < Duration::from_millis(800), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn cancel_kills_the_running_script() {
+        let generation = AtomicU64::new(0);
+        let start = Instant::now();
+        let success = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                generation.fetch_add(1, Ordering::SeqCst);
+            });
+            job().run_task(&sh("sleep 5"), None, &generation)
+        });
+        assert!(!success);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn cancel_skips_queued_jobs() {
+        let dir = temp_dir("cancel");
+        let skipped = dir.join("skipped");
+        let runner = ScriptRunner::spawn("test", None);
+        runner.run(vec![sh("sleep 5")], &[], None);
+        runner.run(vec![sh(&format!("touch {}", skipped.display()))], &[], None);
+        let start = Instant::now();
+        runner.cancel();
+        drop(runner);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(!skipped.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn collect_chunks_stops_at_the_timeout() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(b"partial ".to_vec()).unwrap();
+        sender.send(b"output".to_vec()).unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            collect_chunks(&receiver, Duration::from_millis(50)),
+            b"partial output"
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        drop(sender);
+        assert_eq!(collect_chunks(&receiver, Duration::from_secs(5)), b"");
+    }
+
+    fn group(tasks: Vec<Task>, parallel: bool) -> Task {
+        Task {
+            kind: TaskKind::Group { tasks, parallel },
+            stop_on_error: false,
+        }
+    }
+
+    fn stop_on_error(task: Task) -> Task {
+        Task {
+            stop_on_error: true,
+            ..task
+        }
+    }
+
+    #[test]
+    fn failures_continue_by_default() {
+        let dir = temp_dir("continue");
+        let touch = sh(&format!("touch '{}'/after", dir.display()));
+        assert!(!run(&group(vec![sh("exit 1"), touch], false), None).0);
+        assert!(dir.join("after").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stop_on_error_skips_the_remaining_tasks() {
+        let dir = temp_dir("stop");
+        let touch = |name: &str| sh(&format!("touch '{}'/{}", dir.display(), name));
+        let tasks = vec![
+            touch("first"),
+            stop_on_error(sh("exit 1")),
+            touch("skipped"),
+        ];
+        assert!(!run(&group(tasks, false), None).0);
+        assert!(dir.join("first").exists());
+        assert!(!dir.join("skipped").exists());
+
+        // Only the task that failed decides whether to stop
+        let tasks = vec![stop_on_error(touch("ok")), sh("exit 1"), touch("ran")];
+        assert!(!run(&group(tasks, false), None).0);
+        assert!(dir.join("ran").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_groups_stop_the_enclosing_list() {
+        let dir = temp_dir("nested");
+        let inner = stop_on_error(group(vec![sh("exit 0"), sh("exit 1")], true));
+        let touch = sh(&format!("touch '{}'/skipped", dir.display()));
+        assert!(!run(&group(vec![inner, touch], false), None).0);
+        assert!(!dir.join("skipped").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parallel_groups_run_at_once_and_fail_if_any_task_fails() {
+        let (success, elapsed) = run(
+            &group((0..3).map(|_| sh("sleep 0.3")).collect(), true),
+            None,
+        );
+        assert!(success);
+        assert!(elapsed < Duration::from_millis(800), "took {:?}", elapsed);
+
+        // Every task runs, even after one failed
+        let dir = temp_dir("parallel");
+        let touch = sh(&format!("sleep 0.1; touch '{}'/ran", dir.display()));
+        let tasks = vec![stop_on_error(sh("exit 1")), touch];
+        assert!(!run(&group(tasks, true), None).0);
+        assert!(dir.join("ran").exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(run(&group(Vec::new(), true), None).0);
+    }
+
+    fn files(dir: &std::path::Path) -> Vec<String> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn write_file_creates_missing_directories() {
+        let dir = temp_dir("write-new");
+        let path = dir.join("state").join("layer");
+        write_file(path.to_str().unwrap(), "nav\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "nav\n");
+        assert_eq!(files(&dir.join("state")), ["layer"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_file_replaces_the_file_instead_of_truncating_it() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = temp_dir("write-replace");
+        let path = dir.join("layer");
+        fs::write(&path, "base\n").unwrap();
+        let mut reader = fs::File::open(&path).unwrap();
+        let inode = fs::metadata(&path).unwrap().ino();
+
+        write_file(path.to_str().unwrap(), "nav\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "nav\n");
+        assert_ne!(fs::metadata(&path).unwrap().ino(), inode);
+        // A reader that opened the old file still sees all of it
+        let mut old = String::new();
+        reader.read_to_string(&mut old).unwrap();
+        assert_eq!(old, "base\n");
+        assert_eq!(files(&dir), ["layer"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_file_removes_the_temporary_file_on_failure() {
+        let dir = temp_dir("write-fail");
+        let path = dir.join("layer");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("keep"), "").unwrap();
+        assert!(write_file(path.to_str().unwrap(), "nav\n").is_err());
+        assert_eq!(files(&dir), ["layer"]);
+
+        let task = Task {
+            kind: TaskKind::WriteFile {
+                path: path.to_str().unwrap().to_string(),
+                contents: "nav\n".to_string(),
+            },
+            stop_on_error: false,
+        };
+        assert!(!run(&task, None).0);
+        assert_eq!(files(&dir), ["layer"]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let error = write_file("/", "nav\n").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn parallel_writes_to_the_same_file_do_not_collide() {
+        let dir = temp_dir("write-parallel");
+        let path = dir.join("layer");
+        let tasks = (0..8)
+            .map(|i| Task {
+                kind: TaskKind::WriteFile {
+                    path: path.to_str().unwrap().to_string(),
+                    contents: format!("layer{}\n", i),
+                },
+                stop_on_error: false,
+            })
+            .collect();
+        assert!(run(&group(tasks, true), None).0);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("layer") && contents.ends_with('\n'));
+        assert_eq!(files(&dir), ["layer"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
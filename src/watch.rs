@@ -0,0 +1,160 @@
+use crate::config::BroadcastConfig;
+use crate::EXIT_RECONNECT_GAVE_UP;
+use anyhow::{bail, Context};
+use kanata_layer_observer::{
+    Backoff, ClientMessage, Event, KanataAddr, KanataClient, ServerMessage,
+};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpStream};
+use std::time::Duration;
+
+/// How long `--once` waits for the current layer
+const ONCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Output options of the `watch` subcommand
+pub struct WatchOptions {
+    /// Print every message as a line of JSON instead of only layer names
+    pub json: bool,
+    /// Exit after printing the current layer
+    pub once: bool,
+}
+
+/// Prints kanata's messages, reconnecting until the backoff gives up, and returns
+/// the exit code
+pub fn watch_kanata(addr: KanataAddr, backoff: Backoff, options: &WatchOptions) -> i32 {
+    if options.once {
+        let result = KanataClient::connect(addr.clone()).and_then(|mut client| {
+            client.set_read_timeout(Some(ONCE_TIMEOUT))?;
+            client.send(&ClientMessage::RequestCurrentLayerName {})?;
+            loop {
+                if print(&client.recv()?, options)? {
+                    return Ok(());
+                }
+            }
+        });
+        return match result {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!(
+                    "Failed to get the current layer from kanata at {}: {}",
+                    addr, e
+                );
+                1
+            }
+        };
+    }
+
+    let mut client = KanataClient::new(addr).with_backoff(backoff);
+    while let Some(event) = client.next() {
+        let printed = match event {
+            Event::Connected => {
+                // Print the current layer right away instead of waiting for a change
+                if let Err(e) = client.send(&ClientMessage::RequestCurrentLayerName {}) {
+                    log::error!("failed to request the current layer: {}", e);
+                }
+                Ok(false)
+            }
+            Event::Message(msg) => print(&msg, options),
+            Event::ConnectFailed { error, .. } => {
+                log::error!("failed to connect to kanata: {}", error);
+                Ok(false)
+            }
+            Event::Disconnected { error, .. } => {
+                log::error!("connection lost: {}", error);
+                Ok(false)
+            }
+        };
+        // Stdout was closed, e.g. by `head`
+        if printed.is_err() {
+            return 0;
+        }
+    }
+    log::error!("giving up on connecting to kanata");
+    EXIT_RECONNECT_GAVE_UP
+}
+
+/// Prints the messages re-broadcast by a running observer until it disconnects,
+/// returning the exit code
+pub fn watch_broadcast(broadcast: &BroadcastConfig, options: &WatchOptions) -> i32 {
+    match read_broadcast(broadcast, options) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            1
+        }
+    }
+}
+
+fn read_broadcast(broadcast: &BroadcastConfig, options: &WatchOptions) -> anyhow::Result<()> {
+    let timeout = options.once.then_some(ONCE_TIMEOUT);
+    let reader: Box<dyn BufRead> = match (&broadcast.socket, broadcast.port) {
+        (Some(path), _) => {
+            let path = shellexpand::tilde(path).to_string();
+            let stream = connect_unix(&path, timeout)
+                .with_context(|| format!("failed to connect to the observer at {}", path))?;
+            Box::new(BufReader::new(stream))
+        }
+        (None, Some(port)) => {
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).with_context(|| {
+                format!("failed to connect to the observer at 127.0.0.1:{}", port)
+            })?;
+            stream.set_read_timeout(timeout)?;
+            Box::new(BufReader::new(stream))
+        }
+        (None, None) => bail!("[broadcast] needs a `socket` or `port`"),
+    };
+
+    for line in reader.lines() {
+        let line = line.context("failed to read from the observer")?;
+        let Ok(msg) = serde_json::from_str::<ServerMessage>(&line) else {
+            log::trace!("ignoring line {:?}", line);
+            continue;
+        };
+        match print(&msg, options) {
+            Ok(true) if options.once => return Ok(()),
+            Ok(_) => {}
+            // Stdout was closed, e.g. by `head`
+            Err(_) => return Ok(()),
+        }
+    }
+    bail!("the observer closed the connection")
+}
+
+#[cfg(unix)]
+fn connect_unix(
+    path: &str,
+    timeout: Option<Duration>,
+) -> io::Result<std::os::unix::net::UnixStream> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(timeout)?;
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str, _timeout: Option<Duration>) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// Prints `msg` according to `options`, returning whether it carried the current layer
+fn print(msg: &ServerMessage, options: &WatchOptions) -> io::Result<bool> {
+    let layer = match msg {
+        ServerMessage::LayerChange { new } => Some(new),
+        ServerMessage::CurrentLayerName { name } => Some(name),
+        _ => None,
+    };
+
+    let mut stdout = io::stdout().lock();
+    if options.json {
+        if options.once && layer.is_none() {
+            return Ok(false);
+        }
+        writeln!(stdout, "{}", serde_json::to_string(msg)?)?;
+    } else if let Some(layer) = layer {
+        writeln!(stdout, "{}", layer)?;
+    }
+    stdout.flush()?;
+    Ok(layer.is_some())
+}